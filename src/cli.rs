@@ -0,0 +1,121 @@
+use clap::{ArgGroup, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file to load, defaults to ./config.json or the user config directory
+    #[arg(long, global = true, env = "WI_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Database file to use, defaults to ./db/index.db or the user data directory
+    #[arg(long, global = true, env = "WI_DB")]
+    pub db: Option<PathBuf>,
+    /// Directory epubs are written to, defaults to ./build or the user data directory
+    #[arg(long, global = true, env = "WI_BUILD_DIR")]
+    pub build_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// (Re)build the chapter index from the table of contents
+    Index {
+        /// Update the index even if the table of contents lists far fewer chapters than it has
+        #[arg(long)]
+        force: bool,
+    },
+    /// Download every indexed chapter that has not been downloaded yet
+    Download,
+    /// Download already stored chapters again and mark the ones that changed for regeneration
+    #[command(group(ArgGroup::new("selection").required(true).args(["all", "volume", "days"])))]
+    Refresh {
+        /// Recheck every downloaded chapter
+        #[arg(long)]
+        all: bool,
+        /// Only recheck chapters in this volume, e.g. "Volume 9"
+        #[arg(long)]
+        volume: Option<String>,
+        /// Only recheck chapters that were added to the table of contents in the last N days
+        #[arg(long, value_name = "N")]
+        days: Option<u32>,
+    },
+    /// Rebuild the stored chapter text from the downloaded pages without downloading anything
+    Reprocess,
+    /// Generate epubs for updated volumes and chapters without sending them
+    Build,
+    /// Generate epubs for updated volumes and chapters and mail them to destinations
+    Send,
+    /// Print a summary of the index and any pending work
+    Status,
+    /// List or retry chapters that failed to download
+    Failures {
+        #[command(subcommand)]
+        command: FailuresCommand,
+    },
+    /// List or build earlier versions of an edited chapter
+    Revisions {
+        #[command(subcommand)]
+        command: RevisionsCommand,
+    },
+    /// Show what changed between two revisions of a chapter, paragraph by paragraph
+    Diff {
+        /// Name of the chapter, as shown in the table of contents
+        chapter: String,
+        /// Revision to compare from, defaults to the one before --to
+        #[arg(long)]
+        from: Option<usize>,
+        /// Revision to compare to, defaults to the current one
+        #[arg(long)]
+        to: Option<usize>,
+    },
+    /// Delete the saved cookies, the patreon login is done again on the next run
+    ClearCookies,
+    /// Print which epub variants will be generated and who receives which
+    Plan,
+    /// Index, download, build and send in one go (default when no command is given)
+    Run,
+    /// Inspect the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Load and validate the config, listing every problem found
+    Check,
+    /// Print the effective config after secrets and environment overrides, with passwords masked
+    Show,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FailuresCommand {
+    /// List chapters whose last download failed or never finished
+    List,
+    /// Download failed chapters now instead of waiting for their backoff to pass
+    #[command(group(ArgGroup::new("selection").required(true).args(["all", "chapters"])))]
+    Retry {
+        /// Retry every failed chapter
+        #[arg(long)]
+        all: bool,
+        /// Names of the chapters to retry, as shown by `failures list`
+        chapters: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RevisionsCommand {
+    /// List every stored version of a chapter, newest first
+    List {
+        /// Name of the chapter, as shown in the table of contents
+        chapter: String,
+    },
+    /// Generate an epub of a chapter as it was at one revision
+    Build {
+        /// Id of the revision, as shown by `revisions list`
+        revision: usize,
+    },
+}
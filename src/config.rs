@@ -1,6 +1,13 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+use crate::site;
+use crate::wandering_inn::WanderingInn;
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase", default)]
 pub struct MailConfig {
     pub name: String,
@@ -23,7 +30,7 @@ impl Default for MailConfig {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase", default)]
 pub struct UserConfig {
     pub name: String,
@@ -31,6 +38,10 @@ pub struct UserConfig {
     pub strip_colour: bool,
     pub send_full_volumes: bool,
     pub send_individual_chapters: bool,
+    // also mail the report of what changed in edited chapters
+    pub send_diff_report: bool,
+    // also mail an epub of just the changes to edited chapters
+    pub send_errata: bool,
 }
 impl Default for UserConfig {
     fn default() -> Self {
@@ -40,11 +51,13 @@ impl Default for UserConfig {
             strip_colour: false,
             send_full_volumes: true,
             send_individual_chapters: false,
+            send_diff_report: false,
+            send_errata: false,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase", default)]
 pub struct EpubGenConfig {
     pub volumes: bool,
@@ -62,68 +75,701 @@ impl Default for EpubGenConfig {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct HttpConfig {
+    pub user_agent: String,
+    // seconds to wait for a connection to be established
+    pub connect_timeout: u64,
+    // seconds to wait for each read of a response, a stalled connection fails after this
+    pub read_timeout: u64,
+    // http://, https://, socks5:// or socks5h:// url every request goes through, empty for none
+    pub proxy: String,
+    // sent with every request
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            user_agent: format!("wandering_inn_scraper/{}", env!("CARGO_PKG_VERSION")),
+            connect_timeout: 30,
+            read_timeout: 60,
+            proxy: String::default(),
+            headers: BTreeMap::default(),
+        }
+    }
+}
+
+// Where the scraper finds things on the site, so a theme change can be fixed without a new release.
+// Selectors are tag, .class or tag.class
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct SelectorsConfig {
+    pub volume_wrapper: String,
+    pub volume_title: String,
+    pub chapter_entry: String,
+    pub chapter_link: String,
+    pub entry_content: String,
+    pub entry_title: String,
+    pub password_form: String,
+    // regex matched against chapter titles
+    pub early_access_title: String,
+}
+
+impl Default for SelectorsConfig {
+    fn default() -> Self {
+        SelectorsConfig {
+            volume_wrapper: String::from(".volume-wrapper"),
+            volume_title: String::from("h2"),
+            chapter_entry: String::from(".chapter-entry"),
+            chapter_link: String::from("a"),
+            entry_content: String::from(".entry-content"),
+            entry_title: String::from(".entry-title"),
+            password_form: String::from(".post-password-form"),
+            early_access_title: String::from("(?i)Patron Early Access"),
+        }
+    }
+}
+
+// Everything about wanderinginn.com, used when Series is wandering-inn
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct WanderingInnConfig {
+    pub toc_url: String,
+    pub selectors: SelectorsConfig,
+}
+
+impl Default for WanderingInnConfig {
+    fn default() -> Self {
+        WanderingInnConfig {
+            toc_url: String::from("https://wanderinginn.com/table-of-contents/"),
+            selectors: SelectorsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase", default)]
 pub struct Config {
     pub mail: MailConfig,
     pub epub_gen: EpubGenConfig,
-    pub toc_url: String,
-    // number of seconds to wait before allowing another request to be made
+    pub http: HttpConfig,
+    // which web serial is scraped, the database keeps each series apart
+    pub series: String,
+    pub wandering_inn: WanderingInnConfig,
+    // TocUrl and Selectors from before they moved into the WanderingInn section
+    #[serde(skip_serializing_if = "Option::is_none")]
+    toc_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selectors: Option<SelectorsConfig>,
+    // number of milliseconds to wait before allowing another request to be made
     // avoids being ip banned
     pub request_delay: u64,
+    // optional cap on requests per minute, applied on top of RequestDelay
+    pub requests_per_minute: Option<u32>,
+    // how many chapters are downloaded at once, requests still never go faster than the delay allows
+    pub concurrent_downloads: usize,
+    // how many times a failed request is retried before the chapter is skipped
+    pub max_retries: u32,
     pub patreon_prompt: bool,
+    pub patreon_password: String,
+    // file containing only the patreon password, used when PatreonPassword is empty
+    pub patreon_password_file: String,
+    // separate file holding secrets such as the passwords, merged over this config
+    pub secrets_file: String,
 }
 impl Default for Config {
     fn default() -> Self {
         Config {
-            toc_url: String::from("https://wanderinginn.com/table-of-contents/"),
+            series: String::from(WanderingInn::SERIES),
+            wandering_inn: WanderingInnConfig::default(),
+            toc_url: None,
+            selectors: None,
             request_delay: 1000,
+            requests_per_minute: None,
+            concurrent_downloads: 2,
+            max_retries: 3,
             mail: MailConfig::default(),
             epub_gen: EpubGenConfig::default(),
+            http: HttpConfig::default(),
             patreon_prompt: false,
+            patreon_password: String::default(),
+            patreon_password_file: String::default(),
+            secrets_file: String::default(),
         }
     }
 }
 
-pub fn load_config() -> Config {
-    if !std::path::Path::new("config.json").exists() {
-        println!("No config.json found, using default values");
-        println!("Request delay is 1000ms");
-        return Config::default();
+// requests faster than this risk getting banned, slower than this is almost certainly a typo
+const MIN_REQUEST_DELAY: u64 = 250;
+const MAX_REQUEST_DELAY: u64 = 60_000;
+const MAX_RETRIES: u32 = 10;
+const MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
+impl Config {
+    /// Returns every problem found in the config, empty if it is valid
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::<String>::new();
+
+        // the series and its own section are checked by building its adapter
+        if let Err(site_problems) = site::adapter(self) {
+            problems.extend(site_problems);
+        }
+
+        if !(MIN_REQUEST_DELAY..=MAX_REQUEST_DELAY).contains(&self.request_delay) {
+            problems.push(format!(
+                "RequestDelay must be between {}ms and {}ms, got {}ms",
+                MIN_REQUEST_DELAY, MAX_REQUEST_DELAY, self.request_delay
+            ));
+        }
+
+        if self.requests_per_minute == Some(0) {
+            problems.push(String::from(
+                "RequestsPerMinute must be at least 1, remove it for no cap",
+            ));
+        }
+
+        if !(1..=MAX_CONCURRENT_DOWNLOADS).contains(&self.concurrent_downloads) {
+            problems.push(format!(
+                "ConcurrentDownloads must be between 1 and {}, got {}",
+                MAX_CONCURRENT_DOWNLOADS, self.concurrent_downloads
+            ));
+        }
+
+        if self.max_retries > MAX_RETRIES {
+            problems.push(format!(
+                "MaxRetries must be at most {}, got {}",
+                MAX_RETRIES, self.max_retries
+            ));
+        }
+
+        let http = &self.http;
+        if reqwest::header::HeaderValue::from_str(&http.user_agent).is_err() {
+            problems.push(format!(
+                "Http.UserAgent <{}> is not a valid header value",
+                http.user_agent
+            ));
+        }
+        if http.connect_timeout == 0 {
+            problems.push(String::from(
+                "Http.ConnectTimeout must be at least 1 second",
+            ));
+        }
+        if http.read_timeout == 0 {
+            problems.push(String::from("Http.ReadTimeout must be at least 1 second"));
+        }
+        if !http.proxy.is_empty() {
+            match reqwest::Url::parse(&http.proxy) {
+                Ok(url) if ["http", "https", "socks5", "socks5h"].contains(&url.scheme()) => (),
+                Ok(_) => problems.push(format!(
+                    "Http.Proxy <{}> must be an http, https, socks5 or socks5h url",
+                    http.proxy
+                )),
+                Err(e) => problems.push(format!(
+                    "Http.Proxy <{}> is not a valid url: {}",
+                    http.proxy, e
+                )),
+            }
+        }
+        for (name, value) in &http.headers {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("Http.Headers.{} is not a valid header name", name));
+            }
+            if reqwest::header::HeaderValue::from_str(value).is_err() {
+                problems.push(format!("Http.Headers.{} is not a valid header value", name));
+            }
+        }
+
+        let mail = &self.mail;
+        if mail.smtp_port == 0 {
+            problems.push(String::from("Mail.SmtpPort must not be 0"));
+        }
+        if !mail.destinations.is_empty() {
+            if mail.address.trim().is_empty() {
+                problems.push(String::from(
+                    "Mail.Address is empty but there are destinations to send to",
+                ));
+            }
+            if mail.smtp_hostname.trim().is_empty() {
+                problems.push(String::from(
+                    "Mail.SmtpHostname is empty but there are destinations to send to",
+                ));
+            }
+        }
+        for (i, dest) in mail.destinations.iter().enumerate() {
+            if dest.email.trim().is_empty() {
+                problems.push(format!("Mail.Destinations.{}.Email is empty", i));
+            } else if !dest.email.contains('@') {
+                problems.push(format!(
+                    "Mail.Destinations.{}.Email <{}> is not an email address",
+                    i, dest.email
+                ));
+            }
+            // a destination can be sent only the changes to chapters it already has
+            if !dest.send_full_volumes
+                && !dest.send_individual_chapters
+                && !dest.send_diff_report
+                && !dest.send_errata
+            {
+                problems.push(format!(
+                    "Mail.Destinations.{} has SendFullVolumes, SendIndividualChapters, SendDiffReport and SendErrata all disabled",
+                    i
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Override {
+        source: String,
+        message: String,
+    },
+    Invalid {
+        path: PathBuf,
+        problems: Vec<String>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            ConfigError::Override { source, message } => {
+                write!(f, "invalid value from {}: {}", source, message)
+            }
+            ConfigError::Invalid { path, problems } => {
+                write!(f, "{} has {} problem(s)", path.display(), problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn push_blank(out: &mut String, c: char) {
+    if c == '\n' || c == '\r' {
+        out.push(c);
+    } else {
+        // keep the byte length so serde_json reports the same columns as the original file
+        out.extend(std::iter::repeat_n(' ', c.len_utf8()));
     }
-    match std::fs::read_to_string("config.json") {
-        Ok(str) => match serde_json::from_str::<Config>(&str) {
-            Ok(mut config) => {
-                println!("Loaded config");
-                println!("Delay is {}ms", config.request_delay);
-                println!(
-                    "Sending from <{}> at <{}>",
-                    config.mail.name, config.mail.address
-                );
-                for dest in &config.mail.destinations {
-                    println!("Sending to <{}> at <{}>", dest.name, dest.email);
+}
+
+// Blank out comments and trailing commas so JSONC can be parsed as plain JSON
+// without shifting any line or column numbers
+fn strip_jsonc(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => {
+                    if let Some(escaped) = chars.next() {
+                        out.push(escaped);
+                    }
                 }
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
 
-                for dest in &config.mail.destinations {
-                    if dest.strip_colour {
-                        config.epub_gen.strip_colour = true;
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                push_blank(&mut out, c);
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
                     }
-                    if dest.send_full_volumes {
-                        config.epub_gen.volumes = true;
+                    push_blank(&mut out, next);
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                push_blank(&mut out, c);
+                // the opening star can't also close the comment, as in /*/
+                if let Some(star) = chars.next() {
+                    push_blank(&mut out, star);
+                }
+                let mut prev = ' ';
+                for next in chars.by_ref() {
+                    push_blank(&mut out, next);
+                    if prev == '*' && next == '/' {
+                        break;
                     }
-                    if dest.send_individual_chapters {
-                        config.epub_gen.chapters = true;
+                    prev = next;
+                }
+            }
+            '}' | ']' => {
+                let last = out.char_indices().rev().find(|(_, c)| !c.is_whitespace());
+                if let Some((i, ',')) = last {
+                    out.replace_range(i..i + 1, " ");
+                }
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn parse_error(path: &Path, e: serde_json::Error) -> ConfigError {
+    // serde_json appends the position to its message, it's reported separately here
+    let message = e.to_string();
+    let message = match message.rsplit_once(" at line ") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    };
+    ConfigError::Parse {
+        path: path.to_path_buf(),
+        line: e.line(),
+        column: e.column(),
+        message,
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Returns the parsed config alongside any unknown keys found in it
+fn parse_config(path: &Path, contents: &str) -> Result<(Config, Vec<String>), ConfigError> {
+    let stripped = strip_jsonc(contents);
+    let mut deserializer = serde_json::Deserializer::from_str(&stripped);
+    let mut unknown_keys = Vec::<String>::new();
+
+    let config =
+        serde_ignored::deserialize(&mut deserializer, |key| unknown_keys.push(key.to_string()))
+            .and_then(|config: Config| deserializer.end().map(|_| config))
+            .map_err(|e| parse_error(path, e))?;
+
+    Ok((config, unknown_keys))
+}
+
+// Objects are merged key by key and arrays index by index, anything else is replaced
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
                     }
                 }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) => {
+            for (i, value) in overlay.into_iter().enumerate() {
+                match base.get_mut(i) {
+                    Some(existing) => merge(existing, value),
+                    None => base.push(value),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// SmtpHostname -> SMTP_HOSTNAME
+fn env_key(key: &str) -> String {
+    let mut out = String::with_capacity(key.len() + 4);
+    for (i, c) in key.chars().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            out.push('_');
+        }
+        out.extend(c.to_uppercase());
+    }
+    out
+}
 
-                config
+// Every leaf of the config can be overridden by an environment variable named after its path,
+// e.g. Mail.Password is WI_MAIL_PASSWORD and Mail.Destinations.0.Email is WI_MAIL_DESTINATIONS_0_EMAIL
+fn apply_env_overrides(
+    value: &mut Value,
+    name: &str,
+    applied: &mut Vec<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                apply_env_overrides(child, &format!("{}_{}", name, env_key(key)), applied)?;
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter_mut().enumerate() {
+                apply_env_overrides(child, &format!("{}_{}", name, i), applied)?;
             }
-            Err(e) => {
-                panic!("{}", e);
+        }
+        leaf => {
+            if let Ok(raw) = std::env::var(name) {
+                *leaf = match leaf {
+                    Value::String(_) => Value::String(raw),
+                    _ => serde_json::from_str(&raw).map_err(|e| ConfigError::Override {
+                        source: name.to_string(),
+                        message: e.to_string(),
+                    })?,
+                };
+                applied.push(name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+const MASK: &str = "********";
+
+impl Config {
+    /// The config as JSON with passwords masked, safe to print
+    pub fn masked(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        for pointer in ["/Mail/Password", "/PatreonPassword"] {
+            if let Some(Value::String(secret)) = value.pointer_mut(pointer) {
+                if !secret.is_empty() {
+                    *secret = String::from(MASK);
+                }
             }
-        },
-        Err(e) => {
-            panic!("{}", e);
         }
+        // proxy urls can carry credentials
+        if let Some(Value::String(proxy)) = value.pointer_mut("/Http/Proxy") {
+            if let Ok(mut url) = reqwest::Url::parse(proxy) {
+                if url.password().is_some() && url.set_password(Some(MASK)).is_ok() {
+                    *proxy = url.to_string();
+                }
+            }
+        }
+        // extra headers are usually there for auth tokens or cookies
+        if let Some(Value::Object(headers)) = value.pointer_mut("/Http/Headers") {
+            for header in headers.values_mut() {
+                *header = Value::String(String::from(MASK));
+            }
+        }
+        value
+    }
+}
+
+// paths in the config are relative to the config file rather than the working directory
+fn relative_to(config_path: &Path, path: &str) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(path)
+}
+
+pub fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    let (config, unknown_keys) = if path.exists() {
+        let (config, unknown_keys) = parse_config(path, &read_file(path)?)?;
+        println!("Loaded config from {}", path.display());
+        (config, unknown_keys)
+    } else {
+        println!(
+            "No config found at {}, using default values",
+            path.display()
+        );
+        (Config::default(), Vec::<String>::new())
+    };
+    let mut problems: Vec<String> = unknown_keys
+        .iter()
+        .map(|key| format!("unknown key {}", key))
+        .collect();
+
+    let mut value = serde_json::to_value(&config).unwrap();
+
+    let secrets_file = std::env::var("WI_SECRETS_FILE").unwrap_or(config.secrets_file);
+    if !secrets_file.is_empty() {
+        let secrets_path = relative_to(path, &secrets_file);
+        let stripped = strip_jsonc(&read_file(&secrets_path)?);
+        let secrets: Value =
+            serde_json::from_str(&stripped).map_err(|e| parse_error(&secrets_path, e))?;
+        merge(&mut value, secrets);
+        println!("Loaded secrets from {}", secrets_path.display());
+    }
+
+    let mut applied = Vec::<String>::new();
+    apply_env_overrides(&mut value, "WI", &mut applied)?;
+    for name in applied {
+        println!("Using {} from the environment", name);
+    }
+
+    let mut config: Config = serde_ignored::deserialize(value, |key| {
+        problems.push(format!("unknown key {} in secrets file", key))
+    })
+    .map_err(|e| ConfigError::Override {
+        source: String::from("secrets file or environment"),
+        message: e.to_string(),
+    })?;
+
+    if let Some(toc_url) = config.toc_url.take() {
+        println!("TocUrl has moved to WanderingInn.TocUrl, please move it in the config");
+        config.wandering_inn.toc_url = toc_url;
+    }
+    if let Some(selectors) = config.selectors.take() {
+        println!("Selectors has moved to WanderingInn.Selectors, please move it in the config");
+        config.wandering_inn.selectors = selectors;
+    }
+
+    if config.patreon_password.is_empty() && !config.patreon_password_file.is_empty() {
+        let password_path = relative_to(path, &config.patreon_password_file);
+        config.patreon_password = read_file(&password_path)?.trim().to_string();
+        println!("Loaded patreon password from {}", password_path.display());
+    }
+
+    problems.extend(config.validate());
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid {
+            path: path.to_path_buf(),
+            problems,
+        });
+    }
+
+    println!("Delay is {}ms", config.request_delay);
+    if let Some(per_minute) = config.requests_per_minute {
+        println!("At most {} requests per minute", per_minute);
+    }
+    println!(
+        "Sending from <{}> at <{}>",
+        config.mail.name, config.mail.address
+    );
+    for dest in &config.mail.destinations {
+        println!("Sending to <{}> at <{}>", dest.name, dest.email);
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_jsonc_blanks_comments_in_place() {
+        let input = "{\n  // comment\n  \"a\": 1, /* block */ \"b\": 2\n}";
+        let stripped = strip_jsonc(input);
+        assert_eq!(stripped.len(), input.len());
+        assert_eq!(stripped.lines().count(), input.lines().count());
+        let value: serde_json::Value = serde_json::from_str(&stripped).unwrap();
+        assert_eq!(value, serde_json::json!({"a": 1, "b": 2}));
+    }
+
+    #[test]
+    fn strip_jsonc_block_comment_needs_its_own_closing_star() {
+        let input = "{\"a\": 1 /*/ note */, \"b\": 2}";
+        let stripped = strip_jsonc(input);
+        assert_eq!(stripped.len(), input.len());
+        let value: serde_json::Value = serde_json::from_str(&stripped).unwrap();
+        assert_eq!(value, serde_json::json!({"a": 1, "b": 2}));
+    }
+
+    #[test]
+    fn strip_jsonc_keeps_comment_markers_in_strings() {
+        let input = r#"{"url": "http://example.com/*x*/", "quote": "a \"// b\""}"#;
+        assert_eq!(strip_jsonc(input), input);
+    }
+
+    #[test]
+    fn strip_jsonc_removes_trailing_commas() {
+        let stripped = strip_jsonc("{\"a\": [1, 2,\n],\n}");
+        let value: serde_json::Value = serde_json::from_str(&stripped).unwrap();
+        assert_eq!(value, serde_json::json!({"a": [1, 2]}));
+    }
+
+    #[test]
+    fn validate_needs_something_to_send_each_destination() {
+        let mut config = Config::default();
+        config.mail.address = String::from("me@example.com");
+        let nothing = UserConfig {
+            email: String::from("reader@example.com"),
+            send_full_volumes: false,
+            ..UserConfig::default()
+        };
+        config.mail.destinations = vec![
+            nothing.clone(),
+            UserConfig {
+                send_errata: true,
+                ..nothing.clone()
+            },
+            UserConfig {
+                send_diff_report: true,
+                ..nothing
+            },
+        ];
+        let problems: Vec<String> = config
+            .validate()
+            .into_iter()
+            .filter(|problem| problem.starts_with("Mail.Destinations."))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![String::from(
+                "Mail.Destinations.0 has SendFullVolumes, SendIndividualChapters, SendDiffReport and SendErrata all disabled"
+            )]
+        );
+    }
+
+    #[test]
+    fn merge_overlays_nested_values() {
+        let mut base = serde_json::json!({
+            "Mail": {"Address": "a@example.com", "Password": "", "Destinations": [{"Email": "x"}, {"Email": "y"}]},
+            "RequestDelay": 1000
+        });
+        let secrets = serde_json::json!({
+            "Mail": {"Password": "secret", "Destinations": [{"Name": "first"}]}
+        });
+        merge(&mut base, secrets);
+        assert_eq!(
+            base,
+            serde_json::json!({
+                "Mail": {
+                    "Address": "a@example.com",
+                    "Password": "secret",
+                    "Destinations": [{"Email": "x", "Name": "first"}, {"Email": "y"}]
+                },
+                "RequestDelay": 1000
+            })
+        );
+    }
+
+    #[test]
+    fn env_key_splits_pascal_case() {
+        assert_eq!(env_key("SmtpHostname"), "SMTP_HOSTNAME");
+        assert_eq!(env_key("Mail"), "MAIL");
+        assert_eq!(env_key("RequestsPerMinute"), "REQUESTS_PER_MINUTE");
     }
 }
@@ -0,0 +1,42 @@
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use std::{fs, io::BufReader, path::Path};
+
+/// Loads the cookies saved by the previous run, starting with an empty jar if there are none
+/// or the file can't be read
+pub fn load(path: &Path) -> CookieStore {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return CookieStore::default(),
+    };
+    match cookie_store::serde::json::load(BufReader::new(file)) {
+        Ok(store) => {
+            println!("Loaded cookies from {}", path.display());
+            store
+        }
+        Err(e) => {
+            println!("Ignoring unreadable cookies in {}: {}", path.display(), e);
+            CookieStore::default()
+        }
+    }
+}
+
+// Only cookies that haven't expired and outlive the session are kept, same as a browser would
+pub fn save(cookies: &CookieStoreMutex, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(path)?;
+    let store = cookies.lock().map_err(|_| "cookie store lock poisoned")?;
+    cookie_store::serde::json::save(&store, &mut file)
+        .map_err(|e| format!("Failed to save cookies to {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Removes the saved cookies, returns whether there were any
+pub fn clear(path: &Path) -> Result<bool, std::io::Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
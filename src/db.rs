@@ -1,12 +1,50 @@
 use rusqlite::{Connection, OptionalExtension, Params, Result};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct Chapter {
     pub id: usize,
     pub name: String,
     pub uri: String,
-    pub _volumeid: usize,
-    pub _data_id: usize,
+    pub volume_id: usize,
+    pub data_id: usize,
+}
+
+/// Which already downloaded chapters to check for edits
+pub enum ChapterSelection {
+    All,
+    Volume(String),
+    // chapters first indexed within this many days
+    RecentDays(u32),
+}
+
+/// HTTP cache validators from the last time a chapter was downloaded
+#[derive(Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A distinct version of a chapter's text, kept when the author edits it
+pub struct Revision {
+    pub id: usize,
+    pub chapter_id: usize,
+    // None for text stored before revisions were kept
+    pub fetched_at: Option<u64>,
+    pub hash: String,
+    pub data: String,
+}
+
+/// The last failed or unfinished download of a chapter
+pub struct DownloadAttempt {
+    pub chapter_id: usize,
+    pub chapter_name: String,
+    pub attempts: u32,
+    // None if the run stopped before the download finished
+    pub last_error: Option<String>,
+    // unix timestamp
+    pub last_try: u64,
 }
 
 pub struct Volume {
@@ -14,17 +52,22 @@ pub struct Volume {
     pub name: String,
 }
 
-pub fn open() -> Result<Connection> {
-    std::fs::create_dir_all(Path::new("db")).unwrap();
-
-    let conn = Connection::open("db/index.db")?;
+pub fn open(path: &Path) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    Ok(setup(Connection::open(path)?)?)
+}
 
+/// Creates the tables of a new database, or brings an older one up to date
+pub fn setup(mut conn: Connection) -> Result<Connection> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS volumes(
         id INTEGER PRIMARY KEY AUTOINCREMENT,
         name TEXT NOT NULL,
         regenerate_epub INTEGER DEFAULT 0 CHECK(regenerate_epub IN (0, 1)),
-        UNIQUE(name)
+        series TEXT NOT NULL,
+        UNIQUE(series, name)
     )",
         (),
     )?;
@@ -55,33 +98,327 @@ pub fn open() -> Result<Connection> {
         (),
     )?;
 
+    // columns added after the tables were first released
+    add_column(
+        &conn,
+        "chapters",
+        "early_access",
+        "INTEGER DEFAULT 0 CHECK(early_access IN (0, 1))",
+    )?;
+    add_column(
+        &conn,
+        "chapters",
+        "refetch",
+        "INTEGER DEFAULT 0 CHECK(refetch IN (0, 1))",
+    )?;
+    // unix timestamp of when the chapter first showed up in the table of contents, NULL for
+    // chapters from the first index of a series and ones indexed before this was recorded
+    add_column(&conn, "chapters", "indexed_at", "INTEGER")?;
+    add_column(&conn, "chapters", "etag", "TEXT")?;
+    add_column(&conn, "chapters", "last_modified", "TEXT")?;
+    // order in the table of contents, chapters are numbered across all volumes
+    add_column(&conn, "volumes", "position", "INTEGER")?;
+    add_column(&conn, "chapters", "position", "INTEGER")?;
+    // no longer listed in the table of contents, kept so the download isn't lost if it comes back
+    add_column(
+        &conn,
+        "chapters",
+        "removed",
+        "INTEGER DEFAULT 0 CHECK(removed IN (0, 1))",
+    )?;
+    add_volume_series(&mut conn)?;
+
+    // every distinct version of a chapter along with the page exactly as downloaded,
+    // raw_data only holds the latest text
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chapter_revisions(
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chapter_id INTEGER NOT NULL,
+        fetched_at INTEGER,
+        hash TEXT NOT NULL,
+        html TEXT,
+        data TEXT NOT NULL,
+        FOREIGN KEY(chapter_id) REFERENCES chapters(id)
+    )",
+        (),
+    )?;
+    add_initial_revisions(&mut conn)?;
+    // edits already included in a diff report
+    add_column(
+        &conn,
+        "chapter_revisions",
+        "reported",
+        "INTEGER DEFAULT 0 CHECK(reported IN (0, 1))",
+    )?;
+
+    // files that were built but not mailed yet, kept until a send succeeds
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS unsent_attachments(
+        path TEXT PRIMARY KEY,
+        kind TEXT NOT NULL
+    )",
+        (),
+    )?;
+    // attachments built before multiple series were supported belong to the wandering inn
+    add_column(
+        &conn,
+        "unsent_attachments",
+        "series",
+        &format!("TEXT NOT NULL DEFAULT '{}'", LEGACY_SERIES),
+    )?;
+
+    // one row per chapter whose last download failed or never finished, removed once it succeeds
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS download_attempts(
+        chapter_id INTEGER PRIMARY KEY,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        last_try INTEGER NOT NULL,
+        FOREIGN KEY(chapter_id) REFERENCES chapters(id)
+    )",
+        (),
+    )?;
+
     Ok(conn)
 }
 
-pub fn add_volume(db_conn: &Connection, name: &String) -> Result<usize> {
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.prepare(&format!(
+        "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+        table
+    ))?
+    .exists([column])
+}
+
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+// everything stored before multiple series were supported came from the wandering inn
+const LEGACY_SERIES: &str = "wandering-inn";
+
+// Volume names are only unique within a series, sqlite can't change a unique constraint
+// so older volume tables are copied into a new one, foreign keys are turned off while the
+// chapters briefly point at nothing. They can only be switched outside a transaction
+fn add_volume_series(conn: &mut Connection) -> Result<()> {
+    if has_column(conn, "volumes", "series")? {
+        return Ok(());
+    }
+    let foreign_keys: bool = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let copied = copy_volumes_by_series(conn);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    copied
+}
+
+// Dropping the transaction without committing rolls everything back
+fn copy_volumes_by_series(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(&format!(
+        "CREATE TABLE volumes_by_series(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            regenerate_epub INTEGER DEFAULT 0 CHECK(regenerate_epub IN (0, 1)),
+            series TEXT NOT NULL,
+            position INTEGER,
+            UNIQUE(series, name)
+        );
+        INSERT INTO volumes_by_series(id, name, regenerate_epub, series, position)
+            SELECT id, name, regenerate_epub, '{}', position FROM volumes;
+        DROP TABLE volumes;
+        ALTER TABLE volumes_by_series RENAME TO volumes;",
+        LEGACY_SERIES
+    ))?;
+    if tx.prepare("PRAGMA foreign_key_check")?.exists([])? {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(String::from(
+                "chapters point at volumes that don't exist, not adding series to volumes",
+            )),
+        ));
+    }
+    tx.commit()
+}
+
+// Chapters downloaded before revisions were kept start with their stored text as the first
+// revision, along with the page it was processed from once pages were kept
+fn add_initial_revisions(conn: &mut Connection) -> Result<()> {
+    let has_pages = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'chapter_pages'")?
+        .exists([])?;
+    let tx = conn.transaction()?;
+    let stored: Vec<(usize, Option<String>, String)> = tx
+        .prepare(&format!(
+            "SELECT chapter_id, {}, data FROM raw_data
+            WHERE data IS NOT NULL
+            AND chapter_id NOT IN (SELECT chapter_id FROM chapter_revisions)",
+            if has_pages {
+                "(SELECT html FROM chapter_pages WHERE chapter_pages.chapter_id = raw_data.chapter_id)"
+            } else {
+                "NULL"
+            }
+        ))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_>>()?;
+    for (chapter_id, html, data) in stored {
+        tx.execute(
+            "INSERT INTO chapter_revisions(chapter_id, hash, html, data) values(?1, ?2, ?3, ?4)",
+            (chapter_id, content_hash(&data), html, data),
+        )?;
+    }
+    // the pages are only kept in the revisions from now on
+    if has_pages {
+        tx.execute("DROP TABLE chapter_pages", ())?;
+    }
+    tx.commit()
+}
+
+/// Hex sha256 of a chapter's processed text, revisions with the same hash have the same text
+pub fn content_hash(data: &str) -> String {
+    hex::encode(Sha256::digest(data.as_bytes()))
+}
+
+// chapters belong to a series through their volume, the series is always the first parameter
+const IN_SERIES: &str = "volumeid IN (SELECT id FROM volumes WHERE series = ?1)";
+
+pub fn add_unsent(db_conn: &Connection, series: &str, kind: &str, path: &Path) -> Result<()> {
     db_conn
-        .prepare("INSERT OR IGNORE INTO volumes(name) values(?1)")?
-        .execute([name])?;
-    db_conn.query_row("SELECT id FROM volumes WHERE name = ?1", [name], |row| {
-        row.get(0)
-    })
+        .prepare(
+            "INSERT OR REPLACE INTO unsent_attachments(path, series, kind) values(?1, ?2, ?3)",
+        )?
+        .execute((path.to_string_lossy(), series, kind))?;
+    Ok(())
+}
+
+/// Kind and path of every file waiting to be sent, in the order they were built
+pub fn get_unsent(db_conn: &Connection, series: &str) -> Result<Vec<(String, PathBuf)>> {
+    db_conn
+        .prepare("SELECT kind, path FROM unsent_attachments WHERE series = ?1 ORDER BY rowid")?
+        .query_map([series], |row| {
+            Ok((row.get(0)?, PathBuf::from(row.get::<_, String>(1)?)))
+        })?
+        .collect()
+}
+
+pub fn clear_unsent(db_conn: &Connection, series: &str) -> Result<()> {
+    db_conn
+        .prepare("DELETE FROM unsent_attachments WHERE series = ?1")?
+        .execute([series])?;
+    Ok(())
 }
 
-pub fn add_chapter(db_conn: &Connection, name: String, uri: String, volume: usize) -> Result<()> {
+pub fn add_volume(
+    db_conn: &Connection,
+    series: &str,
+    name: &str,
+    position: usize,
+) -> Result<usize> {
     db_conn
-        .prepare("INSERT OR IGNORE INTO chapters(name, uri, volumeid) values(?1, ?2, ?3)")?
-        .execute((name, uri, volume))?;
+        .prepare("INSERT OR IGNORE INTO volumes(series, name) values(?1, ?2)")?
+        .execute([series, name])?;
+    db_conn
+        .prepare("UPDATE volumes SET position = ?1 WHERE series = ?2 AND name = ?3")?
+        .execute((position, series, name))?;
+    db_conn.query_row(
+        "SELECT id FROM volumes WHERE series = ?1 AND name = ?2",
+        [series, name],
+        |row| row.get(0),
+    )
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn add_chapter(
+    db_conn: &Connection,
+    name: &str,
+    uri: &str,
+    volume: usize,
+    position: usize,
+    indexed_at: Option<u64>,
+) -> Result<()> {
+    db_conn
+        .prepare(
+            "INSERT OR IGNORE INTO chapters(name, uri, volumeid, indexed_at, position)
+            values(?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute((name, uri, volume, indexed_at, position))?;
     Ok(())
 }
 
+/// Every chapter in the index including removed ones, alongside whether it was removed
+pub fn get_indexed_chapters(db_conn: &Connection, series: &str) -> Result<Vec<(Chapter, bool)>> {
+    db_conn
+        .prepare(&format!(
+            "SELECT id, name, uri, volumeid, data_id, removed FROM chapters WHERE {} ORDER BY id",
+            IN_SERIES
+        ))?
+        .query_map([series], |row| {
+            Ok((
+                Chapter {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    uri: row.get(2)?,
+                    volume_id: row.get(3)?,
+                    data_id: row.get(4).unwrap_or(0),
+                },
+                row.get(5)?,
+            ))
+        })?
+        .collect()
+}
+
+// Brings an existing chapter in line with its table of contents entry, restoring it if it was removed
+pub fn update_indexed_chapter(
+    db_conn: &Connection,
+    chapter_id: usize,
+    name: &str,
+    volume: usize,
+    position: usize,
+) -> Result<()> {
+    db_conn
+        .prepare(
+            "UPDATE chapters SET name = ?1, volumeid = ?2, position = ?3, removed = 0 WHERE id = ?4",
+        )?
+        .execute((name, volume, position, chapter_id))?;
+    Ok(())
+}
+
+pub fn mark_removed(db_conn: &Connection, chapter_id: usize) -> Result<()> {
+    db_conn
+        .prepare("UPDATE chapters SET removed = 1, regenerate_epub = 0, refetch = 0 WHERE id = ?1")?
+        .execute([chapter_id])?;
+    clear_download_attempts(db_conn, chapter_id)
+}
+
 pub fn remove_chapter(db_conn: &Connection, chapter_id: usize) -> Result<()> {
+    clear_download_attempts(db_conn, chapter_id)?;
+    db_conn
+        .prepare("DELETE FROM chapter_revisions WHERE chapter_id = ?1")?
+        .execute([chapter_id])?;
     db_conn
         .prepare("DELETE FROM chapters WHERE id = ?1")?
         .execute([chapter_id])?;
     Ok(())
 }
 
-pub fn add_chapter_data(db_conn: &Connection, chapter_id: usize, data: &String) -> Result<()> {
+pub fn add_chapter_data(
+    db_conn: &Connection,
+    chapter_id: usize,
+    html: &str,
+    data: &String,
+    early_access: bool,
+) -> Result<bool> {
     let existing_data: String = (db_conn
         .query_row(
             "SELECT data FROM raw_data WHERE chapter_id = ?1",
@@ -91,27 +428,232 @@ pub fn add_chapter_data(db_conn: &Connection, chapter_id: usize, data: &String)
         .optional()?)
     .unwrap_or_default();
 
+    // updated in place, replacing the row would leave the chapter pointing at a deleted id
     db_conn
-        .prepare("INSERT OR REPLACE INTO raw_data(data, chapter_id) values(?1, ?2)")?
+        .prepare(
+            "INSERT INTO raw_data(data, chapter_id) values(?1, ?2)
+            ON CONFLICT(chapter_id) DO UPDATE SET data = excluded.data",
+        )?
         .execute((data, chapter_id))?;
 
     let regenerate = !existing_data.eq(data);
-    let data_id: usize =
-        db_conn.query_row("SELECT id FROM raw_data WHERE data = ?1", [data], |row| {
-            row.get(0)
-        })?;
+    let data_id: usize = db_conn.query_row(
+        "SELECT id FROM raw_data WHERE chapter_id = ?1",
+        [chapter_id],
+        |row| row.get(0),
+    )?;
+    // an unchanged refetch must not clear a regeneration that's still pending
     db_conn
-        .prepare("UPDATE chapters SET data_id = ?1, regenerate_epub = ?2 WHERE id = ?3")?
-        .execute([data_id, regenerate as usize, chapter_id])?;
+        .prepare(
+            "UPDATE chapters SET data_id = ?1, regenerate_epub = (regenerate_epub OR ?2),
+            early_access = ?3, refetch = 0 WHERE id = ?4",
+        )?
+        .execute([
+            data_id,
+            regenerate as usize,
+            early_access as usize,
+            chapter_id,
+        ])?;
+
+    if regenerate {
+        regenerate_volume_of(db_conn, chapter_id)?;
+    }
 
+    let hash = content_hash(data);
+    let latest: Option<(usize, String)> = db_conn
+        .query_row(
+            "SELECT id, hash FROM chapter_revisions WHERE chapter_id = ?1 ORDER BY id DESC LIMIT 1",
+            [chapter_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    match latest {
+        // revisions stored before pages were kept get the page now, so they can be reprocessed
+        Some((revision_id, latest_hash)) if latest_hash == hash => {
+            db_conn
+                .prepare("UPDATE chapter_revisions SET html = ?1 WHERE id = ?2 AND html IS NULL")?
+                .execute((html, revision_id))?;
+        }
+        _ => {
+            db_conn
+                .prepare(
+                    "INSERT INTO chapter_revisions(chapter_id, fetched_at, hash, html, data)
+                    values(?1, ?2, ?3, ?4, ?5)",
+                )?
+                .execute((chapter_id, unix_now(), hash, html, data))?;
+        }
+    }
+    Ok(regenerate)
+}
+
+/// Every stored version of a chapter, newest first
+pub fn get_revisions(db_conn: &Connection, chapter_id: usize) -> Result<Vec<Revision>> {
+    revision_query_helper(
+        db_conn,
+        "SELECT id, chapter_id, fetched_at, hash, data FROM chapter_revisions
+        WHERE chapter_id = ?1 ORDER BY id DESC",
+        [chapter_id],
+    )
+}
+
+pub fn get_revision(db_conn: &Connection, revision_id: usize) -> Result<Option<Revision>> {
+    Ok(revision_query_helper(
+        db_conn,
+        "SELECT id, chapter_id, fetched_at, hash, data FROM chapter_revisions WHERE id = ?1",
+        [revision_id],
+    )?
+    .pop())
+}
+
+pub fn get_previous_revision(
+    db_conn: &Connection,
+    revision: &Revision,
+) -> Result<Option<Revision>> {
+    Ok(revision_query_helper(
+        db_conn,
+        "SELECT id, chapter_id, fetched_at, hash, data FROM chapter_revisions
+        WHERE chapter_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT 1",
+        [revision.chapter_id, revision.id],
+    )?
+    .pop())
+}
+
+/// Revisions that replaced an earlier version and haven't been in a diff report yet, oldest first
+pub fn get_unreported_revisions(db_conn: &Connection, series: &str) -> Result<Vec<Revision>> {
+    revision_query_helper(
+        db_conn,
+        &format!(
+            "SELECT id, chapter_id, fetched_at, hash, data FROM chapter_revisions AS revision
+            WHERE reported = 0
+            AND chapter_id IN (SELECT id FROM chapters WHERE removed = 0 AND {})
+            AND EXISTS (SELECT 1 FROM chapter_revisions
+                WHERE chapter_id = revision.chapter_id AND id < revision.id)
+            ORDER BY id",
+            IN_SERIES
+        ),
+        [series],
+    )
+}
+
+pub fn mark_revisions_reported(db_conn: &Connection, series: &str) -> Result<()> {
+    db_conn
+        .prepare(&format!(
+            "UPDATE chapter_revisions SET reported = 1
+            WHERE chapter_id IN (SELECT id FROM chapters WHERE {})",
+            IN_SERIES
+        ))?
+        .execute([series])?;
+    Ok(())
+}
+
+fn revision_query_helper<P>(db_conn: &Connection, sql: &str, params: P) -> Result<Vec<Revision>>
+where
+    P: Params,
+{
+    db_conn
+        .prepare(sql)?
+        .query_map(params, |row| {
+            Ok(Revision {
+                id: row.get(0)?,
+                chapter_id: row.get(1)?,
+                fetched_at: row.get(2)?,
+                hash: row.get(3)?,
+                data: row.get(4)?,
+            })
+        })?
+        .collect()
+}
+
+fn regenerate_volume_of(db_conn: &Connection, chapter_id: usize) -> Result<()> {
     let volume_id: usize = db_conn.query_row(
         "SELECT volumeid FROM chapters WHERE id = ?1",
         [chapter_id],
         |row| row.get(0),
     )?;
     db_conn
-        .prepare("UPDATE volumes SET regenerate_epub = ?1 WHERE id = ?2")?
-        .execute([regenerate as usize, volume_id])?;
+        .prepare("UPDATE volumes SET regenerate_epub = 1 WHERE id = ?1")?
+        .execute([volume_id])?;
+    Ok(())
+}
+
+/// Id and downloaded page of every revision of a chapter, oldest first. The page is None for
+/// revisions stored before pages were kept
+pub fn get_revision_pages(
+    db_conn: &Connection,
+    chapter_id: usize,
+) -> Result<Vec<(usize, Option<String>)>> {
+    db_conn
+        .prepare("SELECT id, html FROM chapter_revisions WHERE chapter_id = ?1 ORDER BY id")?
+        .query_map([chapter_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+// Replaces the xhtml of a revision with what its page is processed into now
+pub fn set_revision_data(db_conn: &Connection, revision_id: usize, data: &str) -> Result<()> {
+    db_conn
+        .prepare("UPDATE chapter_revisions SET hash = ?1, data = ?2 WHERE id = ?3")?
+        .execute((content_hash(data), data, revision_id))?;
+    Ok(())
+}
+
+/// Removes revisions with the same text as the one before them, which is left behind when
+/// reprocessing makes two versions of a page come out the same. Returns how many were removed
+pub fn remove_repeated_revisions(db_conn: &Connection, chapter_id: usize) -> Result<usize> {
+    db_conn
+        .prepare(
+            "DELETE FROM chapter_revisions WHERE chapter_id = ?1 AND hash =
+            (SELECT previous.hash FROM chapter_revisions AS previous
+            WHERE previous.chapter_id = chapter_revisions.chapter_id
+            AND previous.id < chapter_revisions.id ORDER BY previous.id DESC LIMIT 1)",
+        )?
+        .execute([chapter_id])
+}
+
+// Replaces the latest xhtml of a chapter, returns whether it changed
+pub fn update_chapter_data(db_conn: &Connection, chapter_id: usize, data: &String) -> Result<bool> {
+    if get_chapter_data(db_conn, chapter_id)?.eq(data) {
+        return Ok(false);
+    }
+    db_conn
+        .prepare("UPDATE raw_data SET data = ?1 WHERE chapter_id = ?2")?
+        .execute((data, chapter_id))?;
+    db_conn
+        .prepare("UPDATE chapters SET regenerate_epub = 1 WHERE id = ?1")?
+        .execute([chapter_id])?;
+    regenerate_volume_of(db_conn, chapter_id)?;
+    Ok(true)
+}
+
+// Chapters stored before their page was kept get no validators, so the next refresh downloads
+// the whole page and it can be reprocessed from then on. Early access chapters get none either,
+// the public version may come back as not modified and the early access text would be kept
+pub fn get_validators(db_conn: &Connection, chapter_id: usize) -> Result<Validators> {
+    Ok(db_conn
+        .query_row(
+            "SELECT etag, last_modified FROM chapters
+            WHERE id = ?1 AND early_access = 0
+            AND (SELECT html FROM chapter_revisions WHERE chapter_id = chapters.id
+            ORDER BY id DESC LIMIT 1) IS NOT NULL",
+            [chapter_id],
+            |row| {
+                Ok(Validators {
+                    etag: row.get(0)?,
+                    last_modified: row.get(1)?,
+                })
+            },
+        )
+        .optional()?
+        .unwrap_or_default())
+}
+
+pub fn set_validators(
+    db_conn: &Connection,
+    chapter_id: usize,
+    validators: &Validators,
+) -> Result<()> {
+    db_conn
+        .prepare("UPDATE chapters SET etag = ?1, last_modified = ?2 WHERE id = ?3")?
+        .execute((&validators.etag, &validators.last_modified, chapter_id))?;
     Ok(())
 }
 
@@ -126,8 +668,8 @@ where
                 id: row.get(0)?,
                 name: row.get(1)?,
                 uri: row.get(2)?,
-                _volumeid: row.get(3)?,
-                _data_id: row.get(4).unwrap_or(0),
+                volume_id: row.get(3)?,
+                data_id: row.get(4).unwrap_or(0),
             })
         })?
         .collect()
@@ -160,32 +702,187 @@ pub fn get_chapter_data(db_conn: &Connection, chapter_id: usize) -> Result<Strin
 pub fn get_chapters_by_volume(db_conn: &Connection, volume_id: usize) -> Result<Vec<Chapter>> {
     chapter_query_helper(
         db_conn,
-        "SELECT id, name, uri, volumeid, data_id FROM chapters WHERE volumeid = ?1",
+        "SELECT id, name, uri, volumeid, data_id FROM chapters WHERE volumeid = ?1 AND removed = 0 ORDER BY position, id",
         [volume_id],
     )
 }
 
-pub fn get_empty_chapters(db_conn: &Connection) -> Result<Vec<Chapter>> {
+pub fn get_chapters_by_name(
+    db_conn: &Connection,
+    series: &str,
+    name: &str,
+) -> Result<Vec<Chapter>> {
+    chapter_query_helper(
+        db_conn,
+        &format!(
+            "SELECT id, name, uri, volumeid, data_id FROM chapters
+            WHERE name = ?2 AND {}
+            ORDER BY position, id",
+            IN_SERIES
+        ),
+        [series, name],
+    )
+}
+
+pub fn get_chapter(db_conn: &Connection, chapter_id: usize) -> Result<Chapter> {
+    chapter_query_helper(
+        db_conn,
+        "SELECT id, name, uri, volumeid, data_id FROM chapters WHERE id = ?1",
+        [chapter_id],
+    )?
+    .pop()
+    .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+pub fn get_downloaded_chapters(db_conn: &Connection, series: &str) -> Result<Vec<Chapter>> {
     chapter_query_helper(
         db_conn,
-        "SELECT id, name, uri, volumeid, data_id FROM chapters WHERE data_id IS NULL",
-        [],
+        &format!(
+            "SELECT id, name, uri, volumeid, data_id FROM chapters
+            WHERE data_id IS NOT NULL AND removed = 0 AND {}
+            ORDER BY position, id",
+            IN_SERIES
+        ),
+        [series],
     )
 }
 
-pub fn get_chapters_to_regenerate(db_conn: &Connection) -> Result<Vec<Chapter>> {
+pub fn get_chapters_to_download(db_conn: &Connection, series: &str) -> Result<Vec<Chapter>> {
     chapter_query_helper(
         db_conn,
-        "SELECT id, name, uri, volumeid, data_id FROM chapters WHERE regenerate_epub = 1",
-        [],
+        &format!(
+            "SELECT id, name, uri, volumeid, data_id FROM chapters
+            WHERE (data_id IS NULL OR refetch = 1) AND removed = 0 AND {}
+            ORDER BY position, id",
+            IN_SERIES
+        ),
+        [series],
     )
 }
 
-pub fn get_volumes_to_regenerate(db_conn: &Connection) -> Result<Vec<Volume>> {
+// Recorded before the request is sent, so a run that dies partway still leaves a trace
+pub fn start_download_attempt(db_conn: &Connection, chapter_id: usize) -> Result<()> {
+    db_conn
+        .prepare(
+            "INSERT INTO download_attempts(chapter_id, attempts, last_error, last_try)
+            values(?1, 1, NULL, ?2)
+            ON CONFLICT(chapter_id) DO UPDATE
+            SET attempts = attempts + 1, last_error = NULL, last_try = excluded.last_try",
+        )?
+        .execute((chapter_id, unix_now()))?;
+    Ok(())
+}
+
+pub fn fail_download_attempt(db_conn: &Connection, chapter_id: usize, error: &str) -> Result<()> {
+    db_conn
+        .prepare("UPDATE download_attempts SET last_error = ?1 WHERE chapter_id = ?2")?
+        .execute((error, chapter_id))?;
+    Ok(())
+}
+
+pub fn clear_download_attempts(db_conn: &Connection, chapter_id: usize) -> Result<()> {
+    db_conn
+        .prepare("DELETE FROM download_attempts WHERE chapter_id = ?1")?
+        .execute([chapter_id])?;
+    Ok(())
+}
+
+pub fn get_download_attempts(db_conn: &Connection, series: &str) -> Result<Vec<DownloadAttempt>> {
+    db_conn
+        .prepare(&format!(
+            "SELECT chapter_id, name, attempts, last_error, last_try FROM download_attempts
+            JOIN chapters ON chapters.id = chapter_id
+            WHERE {}
+            ORDER BY last_try DESC",
+            IN_SERIES
+        ))?
+        .query_map([series], |row| {
+            Ok(DownloadAttempt {
+                chapter_id: row.get(0)?,
+                chapter_name: row.get(1)?,
+                attempts: row.get(2)?,
+                last_error: row.get(3)?,
+                last_try: row.get(4)?,
+            })
+        })?
+        .collect()
+}
+
+/// Flags downloaded chapters to be downloaded again, returns how many were flagged
+pub fn refetch_chapters(
+    db_conn: &Connection,
+    series: &str,
+    selection: &ChapterSelection,
+) -> Result<usize> {
+    let sql = format!(
+        "UPDATE chapters SET refetch = 1 WHERE data_id IS NOT NULL AND removed = 0 AND {}",
+        IN_SERIES
+    );
+    match selection {
+        ChapterSelection::All => db_conn.execute(&sql, [series]),
+        ChapterSelection::Volume(name) => db_conn.execute(
+            &format!(
+                "{} AND volumeid = (SELECT id FROM volumes WHERE series = ?1 AND name = ?2)",
+                sql
+            ),
+            [series, name],
+        ),
+        ChapterSelection::RecentDays(days) => db_conn.execute(
+            &format!("{} AND indexed_at >= ?2", sql),
+            (
+                series,
+                unix_now().saturating_sub(u64::from(*days) * 24 * 60 * 60),
+            ),
+        ),
+    }
+}
+
+/// Flags early access chapters of the series at this uri to be downloaded again, returns how
+/// many were flagged
+pub fn refetch_early_access(db_conn: &Connection, series: &str, uri: &str) -> Result<usize> {
+    db_conn
+        .prepare(&format!(
+            "UPDATE chapters SET refetch = 1 WHERE uri = ?2 AND early_access = 1 AND {}",
+            IN_SERIES
+        ))?
+        .execute([series, uri])
+}
+
+/// Nothing new came of rechecking the chapter, e.g. the server says it hasn't changed
+pub fn clear_refetch(db_conn: &Connection, chapter_id: usize) -> Result<()> {
+    db_conn
+        .prepare("UPDATE chapters SET refetch = 0 WHERE id = ?1")?
+        .execute([chapter_id])?;
+    Ok(())
+}
+
+pub fn get_chapters_to_regenerate(db_conn: &Connection, series: &str) -> Result<Vec<Chapter>> {
+    chapter_query_helper(
+        db_conn,
+        &format!(
+            "SELECT id, name, uri, volumeid, data_id FROM chapters
+            WHERE regenerate_epub = 1 AND removed = 0 AND {}
+            ORDER BY position, id",
+            IN_SERIES
+        ),
+        [series],
+    )
+}
+
+pub fn get_volumes(db_conn: &Connection, series: &str) -> Result<Vec<Volume>> {
     volume_query_helper(
         db_conn,
-        "SELECT id, name FROM volumes WHERE regenerate_epub = 1",
-        [],
+        "SELECT id, name FROM volumes WHERE series = ?1 ORDER BY position, id",
+        [series],
+    )
+}
+
+pub fn get_volumes_to_regenerate(db_conn: &Connection, series: &str) -> Result<Vec<Volume>> {
+    volume_query_helper(
+        db_conn,
+        "SELECT id, name FROM volumes WHERE regenerate_epub = 1 AND series = ?1
+        ORDER BY position, id",
+        [series],
     )
 }
 
@@ -202,3 +899,297 @@ pub fn update_generated_chapter(db_conn: &Connection, id: usize, regenerate: boo
         .execute([regenerate as usize, id])?;
     Ok(())
 }
+
+pub struct Status {
+    pub volumes: usize,
+    pub chapters: usize,
+    pub removed_chapters: usize,
+    pub downloaded_chapters: usize,
+    pub early_access_chapters: usize,
+    pub volumes_to_regenerate: usize,
+    pub chapters_to_regenerate: usize,
+    pub failed_downloads: usize,
+}
+
+pub fn get_status(db_conn: &Connection, series: &str) -> Result<Status> {
+    let count = |sql: &str| db_conn.query_row(sql, [series], |row| row.get::<_, usize>(0));
+    let count_chapters = |condition: &str| {
+        count(&format!(
+            "SELECT COUNT(*) FROM chapters WHERE {} AND {}",
+            condition, IN_SERIES
+        ))
+    };
+    Ok(Status {
+        volumes: count("SELECT COUNT(*) FROM volumes WHERE series = ?1")?,
+        chapters: count_chapters("removed = 0")?,
+        removed_chapters: count_chapters("removed = 1")?,
+        downloaded_chapters: count_chapters("data_id IS NOT NULL AND removed = 0")?,
+        early_access_chapters: count_chapters("early_access = 1")?,
+        volumes_to_regenerate: count(
+            "SELECT COUNT(*) FROM volumes WHERE regenerate_epub = 1 AND series = ?1",
+        )?,
+        chapters_to_regenerate: count_chapters("regenerate_epub = 1")?,
+        failed_downloads: count(&format!(
+            "SELECT COUNT(*) FROM download_attempts JOIN chapters ON chapters.id = chapter_id
+            WHERE last_error IS NOT NULL AND {}",
+            IN_SERIES
+        ))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tables as they were before any migration
+    const BASELINE_SCHEMA: &str = "CREATE TABLE volumes(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            regenerate_epub INTEGER DEFAULT 0 CHECK(regenerate_epub IN (0, 1)),
+            UNIQUE(name)
+        );
+        CREATE TABLE chapters(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            uri TEXT NOT NULL,
+            volumeid INTEGER,
+            data_id INTEGER,
+            regenerate_epub INTEGER DEFAULT 0 CHECK(regenerate_epub IN (0, 1)),
+            FOREIGN KEY(data_id) REFERENCES raw_data(id),
+            FOREIGN KEY(volumeid) REFERENCES volumes(id),
+            UNIQUE(name, uri, volumeid)
+        );
+        CREATE TABLE raw_data(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            chapter_id INTEGER,
+            data TEXT,
+            FOREIGN KEY(chapter_id) REFERENCES chapters(id),
+            UNIQUE(chapter_id)
+        );";
+
+    // two volumes with gaps in their ids, three chapters of which two were downloaded
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+        conn.execute_batch(BASELINE_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO volumes(id, name, regenerate_epub) values(3, 'Volume 1', 0), (7, 'Volume 2', 1);
+            INSERT INTO chapters(id, name, uri, volumeid) values
+                (10, '1.00', 'https://example.com/1-00/', 3),
+                (11, '1.01', 'https://example.com/1-01/', 3),
+                (20, '2.00', 'https://example.com/2-00/', 7);
+            INSERT INTO raw_data(id, chapter_id, data) values(1, 10, 'first'), (2, 20, 'second');
+            UPDATE chapters SET data_id = 1 WHERE id = 10;
+            UPDATE chapters SET data_id = 2 WHERE id = 20;",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn foreign_keys(conn: &Connection) -> bool {
+        conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn foreign_key_violations(conn: &Connection) -> bool {
+        conn.prepare("PRAGMA foreign_key_check")
+            .unwrap()
+            .exists([])
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_baseline_database() {
+        let conn = setup(baseline()).unwrap();
+
+        let volumes: Vec<(usize, String, bool, String, Option<usize>)> = conn
+            .prepare("SELECT id, name, regenerate_epub, series, position FROM volumes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            volumes,
+            vec![
+                (
+                    3,
+                    String::from("Volume 1"),
+                    false,
+                    String::from(LEGACY_SERIES),
+                    None
+                ),
+                (
+                    7,
+                    String::from("Volume 2"),
+                    true,
+                    String::from(LEGACY_SERIES),
+                    None
+                ),
+            ]
+        );
+        assert_eq!(
+            get_volumes(&conn, LEGACY_SERIES)
+                .unwrap()
+                .iter()
+                .map(|volume| volume.id)
+                .collect::<Vec<_>>(),
+            vec![3, 7]
+        );
+        assert!(get_volumes(&conn, "other").unwrap().is_empty());
+
+        // chapters still belong to the same volumes and the new columns have their defaults
+        let chapters: Vec<(usize, usize, bool)> = conn
+            .prepare(
+                "SELECT id, volumeid, early_access = 0 AND refetch = 0 AND removed = 0
+                    AND indexed_at IS NULL AND position IS NULL
+                FROM chapters ORDER BY id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(chapters, vec![(10, 3, true), (11, 3, true), (20, 7, true)]);
+        assert_eq!(
+            get_chapters_by_volume(&conn, 3)
+                .unwrap()
+                .iter()
+                .map(|chapter| chapter.id)
+                .collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+
+        assert!(!foreign_key_violations(&conn));
+        assert!(foreign_keys(&conn));
+
+        // downloaded chapters start with their stored text as the only revision
+        for (chapter_id, data) in [(10, "first"), (20, "second")] {
+            let revisions = get_revisions(&conn, chapter_id).unwrap();
+            assert_eq!(revisions.len(), 1);
+            assert_eq!(revisions[0].data, data);
+            assert_eq!(revisions[0].hash, content_hash(data));
+            assert_eq!(revisions[0].fetched_at, None);
+        }
+        assert!(get_revisions(&conn, 11).unwrap().is_empty());
+        assert!(get_unreported_revisions(&conn, LEGACY_SERIES)
+            .unwrap()
+            .is_empty());
+
+        add_unsent(&conn, LEGACY_SERIES, "volume", Path::new("Volume 1.epub")).unwrap();
+        assert_eq!(get_unsent(&conn, LEGACY_SERIES).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let conn = setup(setup(baseline()).unwrap()).unwrap();
+        assert_eq!(get_volumes(&conn, LEGACY_SERIES).unwrap().len(), 2);
+        assert_eq!(get_revisions(&conn, 10).unwrap().len(), 1);
+        assert_eq!(get_revisions(&conn, 20).unwrap().len(), 1);
+        assert!(!foreign_key_violations(&conn));
+        assert!(foreign_keys(&conn));
+    }
+
+    #[test]
+    fn migrates_stored_pages_and_unsent_attachments() {
+        let conn = baseline();
+        conn.execute_batch(
+            "CREATE TABLE chapter_pages(
+                chapter_id INTEGER PRIMARY KEY,
+                html TEXT NOT NULL,
+                FOREIGN KEY(chapter_id) REFERENCES chapters(id)
+            );
+            INSERT INTO chapter_pages(chapter_id, html) values(10, '<p>first</p>');
+            CREATE TABLE unsent_attachments(
+                path TEXT PRIMARY KEY,
+                kind TEXT NOT NULL
+            );
+            INSERT INTO unsent_attachments(path, kind) values('Volume 2.epub', 'volume');",
+        )
+        .unwrap();
+        let conn = setup(conn).unwrap();
+
+        let pages: Vec<(usize, Option<String>)> = conn
+            .prepare("SELECT chapter_id, html FROM chapter_revisions ORDER BY chapter_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            pages,
+            vec![(10, Some(String::from("<p>first</p>"))), (20, None)]
+        );
+        assert!(!conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'chapter_pages'")
+            .unwrap()
+            .exists([])
+            .unwrap());
+
+        assert_eq!(
+            get_unsent(&conn, LEGACY_SERIES).unwrap(),
+            vec![(String::from("volume"), PathBuf::from("Volume 2.epub"))]
+        );
+        assert!(get_unsent(&conn, "other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn leaves_volumes_alone_when_chapters_point_nowhere() {
+        let mut conn = baseline();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+            INSERT INTO chapters(id, name, uri, volumeid) values(30, '3.00', 'https://example.com/3-00/', 9);
+            PRAGMA foreign_keys = ON;",
+        )
+        .unwrap();
+
+        assert!(add_volume_series(&mut conn).is_err());
+        assert!(!has_column(&conn, "volumes", "series").unwrap());
+        assert_eq!(
+            conn.query_row("SELECT COUNT(*) FROM volumes", [], |row| row
+                .get::<_, usize>(0))
+                .unwrap(),
+            2
+        );
+        assert!(foreign_keys(&conn));
+    }
+
+    #[test]
+    fn refetches_early_access_only_in_series() {
+        let conn = setup(Connection::open_in_memory().unwrap()).unwrap();
+        let uri = "https://example.com/shared/";
+        for series in [LEGACY_SERIES, "other"] {
+            let volume = add_volume(&conn, series, "Volume 1", 0).unwrap();
+            add_chapter(&conn, "Shared", uri, volume, 0, None).unwrap();
+        }
+        conn.execute("UPDATE chapters SET early_access = 1", ())
+            .unwrap();
+
+        assert_eq!(refetch_early_access(&conn, LEGACY_SERIES, uri).unwrap(), 1);
+        let refetch: Vec<(String, bool)> = conn
+            .prepare(
+                "SELECT series, refetch FROM chapters JOIN volumes ON volumes.id = volumeid
+                ORDER BY series",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            refetch,
+            vec![
+                (String::from("other"), false),
+                (String::from(LEGACY_SERIES), true)
+            ]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use regex::Regex;
+use rusqlite::Connection;
+use similar::{capture_diff_slices, group_diff_ops, Algorithm, ChangeTag};
+use soup::prelude::*;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::db;
+use crate::mail::Attachment;
+use crate::site::SeriesInfo;
+
+// unchanged paragraphs shown around each change
+const CONTEXT: usize = 1;
+
+/// Styles for the classes used by ChapterDiff::html
+pub const STYLE: &str = ".meta { color: #666; font-style: italic; }
+.hunk { border-left: 3px solid #ccc; padding-left: 1em; margin-bottom: 1em; }
+.same { color: #666; }
+.removed { background: #fdd; text-decoration: line-through; }
+.added { background: #dfd; }
+";
+
+/// Paragraph level differences between two revisions of a chapter
+pub struct ChapterDiff {
+    pub chapter: String,
+    pub from: usize,
+    pub to: usize,
+    // when the newer revision was fetched
+    pub fetched_at: Option<u64>,
+    // runs of changed paragraphs with a little unchanged context around them
+    pub hunks: Vec<Vec<(ChangeTag, String)>>,
+}
+
+// The text of every paragraph, block level tags split paragraphs and any other markup such as
+// colours, links and entities is dropped so only changes to the words show up
+fn paragraphs(xhtml: &str) -> Vec<String> {
+    let body = xhtml.find("<body").map_or(xhtml, |start| &xhtml[start..]);
+    let block_re = Regex::new(
+        r"(?i)<(?:/?(?:p|div|h[1-6]|li|ul|ol|blockquote|table|tr)\b[^>]*|br\s*/?|hr\s*/?)>",
+    )
+    .unwrap();
+    block_re
+        .split(body)
+        .map(|chunk| {
+            Soup::new(chunk)
+                .text()
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        })
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect()
+}
+
+pub fn diff_revisions(chapter: &str, old: &db::Revision, new: &db::Revision) -> ChapterDiff {
+    let old_paragraphs = paragraphs(&old.data);
+    let new_paragraphs = paragraphs(&new.data);
+    let ops = capture_diff_slices(Algorithm::Myers, &old_paragraphs, &new_paragraphs);
+    let hunks = group_diff_ops(ops, CONTEXT)
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .flat_map(|op| op.iter_changes(&old_paragraphs, &new_paragraphs))
+                .map(|change| (change.tag(), change.value()))
+                .collect()
+        })
+        .collect();
+    ChapterDiff {
+        chapter: chapter.to_string(),
+        from: old.id,
+        to: new.id,
+        fetched_at: new.fetched_at,
+        hunks,
+    }
+}
+
+/// When a revision was fetched as an http date
+pub fn fetched(fetched_at: Option<u64>) -> String {
+    match fetched_at {
+        Some(fetched_at) => httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(fetched_at)),
+        None => String::from("before revisions were kept"),
+    }
+}
+
+impl ChapterDiff {
+    /// Number of paragraphs added and removed
+    pub fn counts(&self) -> (usize, usize) {
+        let count = |tag: ChangeTag| {
+            self.hunks
+                .iter()
+                .flatten()
+                .filter(|(change, _)| *change == tag)
+                .count()
+        };
+        (count(ChangeTag::Insert), count(ChangeTag::Delete))
+    }
+
+    /// The changes as xhtml paragraphs, styled by STYLE
+    pub fn html(&self) -> String {
+        let mut html = format!(
+            "<h2>{}</h2>\n<p class=\"meta\">Revision {} to {}, fetched {}</p>\n",
+            escape(&self.chapter),
+            self.from,
+            self.to,
+            fetched(self.fetched_at)
+        );
+        if self.hunks.is_empty() {
+            html.push_str("<p class=\"meta\">Only the markup changed</p>\n");
+        }
+        for hunk in &self.hunks {
+            html.push_str("<div class=\"hunk\">\n");
+            for (tag, paragraph) in hunk {
+                let class = match tag {
+                    ChangeTag::Equal => "same",
+                    ChangeTag::Delete => "removed",
+                    ChangeTag::Insert => "added",
+                };
+                html.push_str(&format!(
+                    "<p class=\"{}\">{}</p>\n",
+                    class,
+                    escape(paragraph)
+                ));
+            }
+            html.push_str("</div>\n");
+        }
+        html
+    }
+}
+
+impl fmt::Display for ChapterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, revision {} to {}, fetched {}",
+            self.chapter,
+            self.from,
+            self.to,
+            fetched(self.fetched_at)
+        )?;
+        if self.hunks.is_empty() {
+            write!(f, "\n  only the markup changed")?;
+        }
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n  ...")?;
+            }
+            for (tag, paragraph) in hunk {
+                let marker = match tag {
+                    ChangeTag::Equal => ' ',
+                    ChangeTag::Delete => '-',
+                    ChangeTag::Insert => '+',
+                };
+                write!(f, "\n{} {}", marker, paragraph)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escapes text for use in html and xhtml
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html_report(title: &str, diffs: &[ChapterDiff]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\"/>
+<title>{title}</title>
+<style>
+body {{ font-family: serif; max-width: 50em; margin: auto; }}
+{style}</style>
+</head>
+<body>
+<h1>{title}</h1>
+",
+        title = escape(title),
+        style = STYLE
+    );
+    for diff in diffs {
+        html.push_str(&diff.html());
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Diffs every edit that hasn't been reported yet against the version before it, oldest first
+pub fn unreported_edits(
+    db_conn: &Connection,
+    series: &str,
+) -> Result<Vec<ChapterDiff>, Box<dyn std::error::Error>> {
+    let mut diffs = Vec::new();
+    for revision in db::get_unreported_revisions(db_conn, series)? {
+        let previous = db::get_previous_revision(db_conn, &revision)?
+            .ok_or("edited revision has no previous revision")?;
+        let chapter = db::get_chapter(db_conn, revision.chapter_id)?;
+        let diff = diff_revisions(&chapter.name, &previous, &revision);
+        let (added, removed) = diff.counts();
+        println!(
+            "{} was edited, {} paragraphs added and {} removed",
+            chapter.name, added, removed
+        );
+        diffs.push(diff);
+    }
+    Ok(diffs)
+}
+
+/// Names the output for a set of edits after the revisions it covers, e.g. "revisions 3-7"
+pub fn revision_range<'a>(diffs: impl IntoIterator<Item = &'a ChapterDiff>) -> String {
+    let mut revisions = diffs.into_iter().map(|diff| diff.to);
+    match (revisions.next(), revisions.last()) {
+        (Some(first), Some(last)) if first != last => format!("revisions {}-{}", first, last),
+        (Some(first), _) => format!("revision {}", first),
+        (None, _) => String::from("no revisions"),
+    }
+}
+
+/// Writes an html report of the edits to the diffs directory in the build directory
+pub fn write_report(
+    diffs: &[ChapterDiff],
+    info: &SeriesInfo,
+    build_dir: &Path,
+) -> Result<Attachment, Box<dyn std::error::Error>> {
+    let filename = format!("{}.html", revision_range(diffs));
+    let html = html_report(&format!("{} edits", info.title), diffs);
+    let output_dir = build_dir.join("diffs");
+    std::fs::create_dir_all(&output_dir)?;
+    let path = output_dir.join(&filename);
+    std::fs::write(&path, &html)?;
+
+    Ok(Attachment {
+        filename,
+        mime: String::from("text/html"),
+        bytes: html.into_bytes(),
+        path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(id: usize, body: &str) -> db::Revision {
+        db::Revision {
+            id,
+            chapter_id: 1,
+            fetched_at: None,
+            hash: String::new(),
+            data: format!(
+                "<html><head><title>1.00</title></head><body>{}</body></html>",
+                body
+            ),
+        }
+    }
+
+    #[test]
+    fn markup_only_changes_have_no_hunks() {
+        let old = revision(1, "<p>Erin made pasta.</p><p>It was good.</p>");
+        let new = revision(
+            2,
+            "<p class=\"x\"><span style=\"color:red\">Erin</span> made   pasta.</p>\n<p>It was good.</p>",
+        );
+        let diff = diff_revisions("1.00", &old, &new);
+        assert!(diff.hunks.is_empty());
+        assert_eq!(diff.counts(), (0, 0));
+    }
+
+    #[test]
+    fn changed_paragraphs_are_removed_and_added() {
+        let old = revision(1, "<p>One.</p><p>Two.</p><p>Three.</p><p>Four.</p>");
+        let new = revision(2, "<p>One.</p><p>Two, edited.</p><p>Three.</p><p>Four.</p>");
+        let diff = diff_revisions("1.00", &old, &new);
+        assert_eq!(diff.counts(), (1, 1));
+        assert_eq!(
+            diff.to_string(),
+            "1.00, revision 1 to 2, fetched before revisions were kept
+  One.
+- Two.
++ Two, edited.
+  Three."
+        );
+    }
+
+    #[test]
+    fn line_breaks_split_paragraphs() {
+        assert_eq!(
+            paragraphs("<body><p>First line<br/>Second line<br>Third</p></body>"),
+            vec!["First line", "Second line", "Third"]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use color_name::Color;
+use color_name::css::Color;
 use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
 use image::io::Reader as ImageReader;
 use image::Rgba;
@@ -7,22 +7,21 @@ use regex::Regex;
 use rusqlite::Connection;
 use rusttype::{Font, Scale};
 use std::{
-    io::{Cursor, Read, Write},
+    io::{Cursor, Write},
     path::{Path, PathBuf},
 };
 
-use crate::config;
 use crate::db;
-use crate::mail::{send_epubs, Attachment};
+use crate::diff::{self, ChapterDiff};
+use crate::mail::Attachment;
+use crate::plan::{BuildPlan, Variant};
+use crate::site::SeriesInfo;
 
 fn generate_cover(
     volume_title: &str,
     output_dir: &Path,
 ) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut img = ImageReader::open("src/assets/cover.png")
-        .unwrap()
-        .decode()
-        .unwrap();
+    let mut img = image::load_from_memory(include_bytes!("assets/cover.png"))?;
 
     let font = Vec::from(include_bytes!("font/RobotoSlab-VariableFont_wght.ttf") as &[u8]);
     let font = Font::try_from_vec(font).unwrap();
@@ -42,11 +41,8 @@ fn generate_cover(
     Ok(path)
 }
 
-fn load_stylesheet() -> String {
-    let mut file = std::fs::File::open("src/assets/style.css").unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    contents
+fn load_stylesheet() -> &'static str {
+    include_str!("assets/style.css")
 }
 
 fn strip_chapter_colour(chapter_data: &str) -> String {
@@ -71,19 +67,20 @@ fn replace_mrsha_write(chapter_data: &str) -> String {
     .to_string()
 }
 
-fn generate_chapter(
-    db_conn: &Connection,
+// Builds a single chapter epub, the cover is kept in the covers directory next to output_dir
+fn chapter_epub(
+    info: &SeriesInfo,
     chapter: &db::Chapter,
+    title: &str,
+    data: &str,
     output_dir: &Path,
-    strip_colour: bool,
-) -> Result<Attachment, Box<dyn std::error::Error>> {
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut output = Vec::<u8>::new();
-    std::fs::create_dir_all(output_dir.join("individual"))?;
 
     let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
-    epub.metadata("author", "pirate aba")?;
+    epub.metadata("author", &info.author)?;
     epub.metadata("lang", "en")?;
-    epub.metadata("title", &chapter.name)?;
+    epub.metadata("title", title)?;
     epub.metadata("generator", "rsauvehoover/wandering-inn-scraper")?;
 
     let cover_img = generate_cover(
@@ -103,33 +100,75 @@ fn generate_chapter(
     )?;
     epub.stylesheet(load_stylesheet().as_bytes())?;
 
-    let mut raw_data = replace_mrsha_write(&db::get_chapter_data(db_conn, chapter.id)?);
-    if strip_colour {
-        raw_data = strip_chapter_colour(&raw_data);
-    }
     epub.add_content(
         EpubContent::new(
             format!("{}({}).xhtml", &chapter.id, &chapter.name),
-            raw_data.as_bytes(),
+            data.as_bytes(),
         )
         .title(&chapter.name),
     )?;
 
     epub.generate(&mut output)?;
+    Ok(output)
+}
+
+fn generate_chapter(
+    db_conn: &Connection,
+    info: &SeriesInfo,
+    chapter: &db::Chapter,
+    output_dir: &Path,
+    strip_colour: bool,
+) -> Result<Attachment, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(output_dir.join("individual"))?;
+
+    let mut raw_data = replace_mrsha_write(&db::get_chapter_data(db_conn, chapter.id)?);
+    if strip_colour {
+        raw_data = strip_chapter_colour(&raw_data);
+    }
+    let output = chapter_epub(info, chapter, &chapter.name, &raw_data, output_dir)?;
 
     let filename = format!("{}({}).epub", &chapter.id, &chapter.name);
 
-    let mut file = std::fs::File::create(output_dir.join("individual").join(&filename))?;
+    let path = output_dir.join("individual").join(&filename);
+    let mut file = std::fs::File::create(&path)?;
     file.write_all(&output)?;
     Ok(Attachment {
         filename,
         mime: String::from("application/epub+zip"),
         bytes: output,
+        path,
     })
 }
 
+/// Generates an epub of a chapter as it was at an earlier revision, returns where it was written
+pub fn generate_revision(
+    info: &SeriesInfo,
+    chapter: &db::Chapter,
+    revision: &db::Revision,
+    build_dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let output_dir = build_dir.join("revisions");
+    std::fs::create_dir_all(&output_dir)?;
+
+    let output = chapter_epub(
+        info,
+        chapter,
+        &format!("{} (revision {})", chapter.name, revision.id),
+        &replace_mrsha_write(&revision.data),
+        &output_dir,
+    )?;
+
+    let path = output_dir.join(format!(
+        "{}({}) revision {}.epub",
+        chapter.id, chapter.name, revision.id
+    ));
+    std::fs::File::create(&path)?.write_all(&output)?;
+    Ok(path)
+}
+
 fn generate_chapters(
     db_conn: &Connection,
+    info: &SeriesInfo,
     chapters: &Vec<db::Chapter>,
     output_dir: &Path,
     strip_colour: bool,
@@ -143,13 +182,13 @@ fn generate_chapters(
     let mut combined_output = Vec::<u8>::new();
     let last_chapter = chapters.last().unwrap();
     let mut combined_epub = EpubBuilder::new(ZipLibrary::new()?)?;
-    combined_epub.metadata("author", "pirate aba")?;
+    combined_epub.metadata("author", &info.author)?;
     combined_epub.metadata("lang", "en")?;
     combined_epub.metadata(
         "title",
         format!(
-            "The Wandering Inn Chapters {}-{}",
-            chapters[0].name, last_chapter.name
+            "{} Chapters {}-{}",
+            info.title, chapters[0].name, last_chapter.name
         ),
     )?;
     combined_epub.metadata("generator", "rsauvehoover/wandering-inn-scraper")?;
@@ -191,11 +230,11 @@ fn generate_chapters(
         )?;
         attachments.push(generate_chapter(
             db_conn,
+            info,
             chapter,
             output_dir,
             strip_colour,
         )?);
-        db::update_generated_chapter(db_conn, chapter.id, false)?;
     }
 
     combined_epub.generate(&mut combined_output)?;
@@ -210,6 +249,7 @@ fn generate_chapters(
 
 fn generate_volume(
     db_conn: &Connection,
+    info: &SeriesInfo,
     volume: &db::Volume,
     chapters: &Vec<db::Chapter>,
     output_dir: &Path,
@@ -218,9 +258,9 @@ fn generate_volume(
     let mut output = Vec::<u8>::new();
 
     let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
-    epub.metadata("author", "pirate aba")?;
+    epub.metadata("author", &info.author)?;
     epub.metadata("lang", "en")?;
-    epub.metadata("title", format!("The Wandering Inn {}", &volume.name))?;
+    epub.metadata("title", format!("{} {}", info.title, &volume.name))?;
     epub.metadata("generator", "rsauvehoover/wandering-inn-scraper")?;
     epub.stylesheet(load_stylesheet().as_bytes())?;
 
@@ -259,28 +299,210 @@ fn generate_volume(
 
     let filename = format!("{}.epub", volume.name);
 
-    let mut file = std::fs::File::create(output_dir.join(format!("{}.epub", volume.name)))?;
+    let path = output_dir.join(&filename);
+    let mut file = std::fs::File::create(&path)?;
     file.write_all(&output)?;
 
     Ok(Attachment {
         filename,
         mime: String::from("application/epub+zip"),
         bytes: output,
+        path,
     })
 }
 
-pub async fn generate_epubs(
+/// Generates a small epub listing what changed in each edited chapter, so readers don't need
+/// the whole chapter or volume again. None if the edits only changed markup
+pub fn generate_errata(
+    info: &SeriesInfo,
+    diffs: &[ChapterDiff],
+    build_dir: &Path,
+) -> Result<Option<Attachment>, Box<dyn std::error::Error>> {
+    // markup only edits have nothing to read again
+    let diffs: Vec<&ChapterDiff> = diffs.iter().filter(|diff| !diff.hunks.is_empty()).collect();
+    if diffs.is_empty() {
+        return Ok(None);
+    }
+    let output_dir = build_dir.join("errata");
+    std::fs::create_dir_all(&output_dir)?;
+    let name = format!("Errata {}", diff::revision_range(diffs.iter().copied()));
+
+    let mut output = Vec::<u8>::new();
+    let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
+    epub.metadata("author", &info.author)?;
+    epub.metadata("lang", "en")?;
+    epub.metadata("title", format!("{} {}", info.title, name))?;
+    epub.metadata("generator", "rsauvehoover/wandering-inn-scraper")?;
+    epub.stylesheet(format!("{}\n{}", load_stylesheet(), diff::STYLE).as_bytes())?;
+
+    let cover_img = generate_cover(&name, &build_dir.join("covers"));
+    let img_file = ImageReader::open(cover_img?)?.decode()?;
+    let mut img_bytes = Vec::new();
+    img_file.write_to(
+        &mut Cursor::new(&mut img_bytes),
+        image::ImageOutputFormat::Png,
+    )?;
+    epub.add_cover_image(
+        output_dir.join(format!("{}.png", name)),
+        img_bytes.as_slice(),
+        "image/png",
+    )?;
+
+    epub.inline_toc();
+
+    for (i, chapter_diff) in diffs.iter().enumerate() {
+        let xhtml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<html xmlns=\"http://www.w3.org/1999/xhtml\">
+<head>
+<title>{}</title>
+<link rel=\"stylesheet\" href=\"stylesheet.css\" type=\"text/css\" />
+</head>
+<body>
+{}</body>
+</html>
+",
+            diff::escape(&chapter_diff.chapter),
+            chapter_diff.html()
+        );
+        epub.add_content(
+            EpubContent::new(format!("{}.xhtml", i), xhtml.as_bytes()).title(&chapter_diff.chapter),
+        )?;
+    }
+
+    epub.generate(&mut output)?;
+
+    let filename = format!("{}.epub", name);
+    let path = output_dir.join(&filename);
+    std::fs::File::create(&path)?.write_all(&output)?;
+    Ok(Some(Attachment {
+        filename,
+        mime: String::from("application/epub+zip"),
+        bytes: output,
+        path,
+    }))
+}
+
+#[derive(Default)]
+pub struct GeneratedEpubs {
+    pub volumes: Vec<Attachment>,
+    pub volumes_stripped: Vec<Attachment>,
+    pub chapters: Vec<Attachment>,
+    pub chapters_stripped: Vec<Attachment>,
+    // html reports of the chapters edited, one for each build since the last send
+    pub diff_reports: Vec<Attachment>,
+    // what changed in the chapters edited, one for each build since the last send
+    pub errata: Vec<Attachment>,
+}
+
+// kinds the diff report and errata are recorded under, epubs use their variant's key
+const DIFF_REPORT: &str = "diff_report";
+const ERRATA: &str = "errata";
+
+impl GeneratedEpubs {
+    pub fn get(&self, variant: Variant) -> &Vec<Attachment> {
+        match variant {
+            Variant::Volumes => &self.volumes,
+            Variant::VolumesStripped => &self.volumes_stripped,
+            Variant::Chapters => &self.chapters,
+            Variant::ChaptersStripped => &self.chapters_stripped,
+        }
+    }
+
+    fn get_mut(&mut self, variant: Variant) -> &mut Vec<Attachment> {
+        match variant {
+            Variant::Volumes => &mut self.volumes,
+            Variant::VolumesStripped => &mut self.volumes_stripped,
+            Variant::Chapters => &mut self.chapters,
+            Variant::ChaptersStripped => &mut self.chapters_stripped,
+        }
+    }
+
+    // Epubs are remembered for the next send as soon as they're written, before their volume
+    // or chapter stops being flagged for regeneration
+    fn add(
+        &mut self,
+        db_conn: &Connection,
+        series: &str,
+        variant: Variant,
+        attachment: Attachment,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        db::add_unsent(db_conn, series, variant.key(), &attachment.path)?;
+        self.get_mut(variant).push(attachment);
+        Ok(())
+    }
+
+    /// Remembers the diff reports and errata until a send succeeds, so `build` followed by
+    /// `send` mails them. The epubs are already remembered by `generate_epubs`
+    pub fn record_unsent(
+        &self,
+        db_conn: &Connection,
+        series: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for report in &self.diff_reports {
+            db::add_unsent(db_conn, series, DIFF_REPORT, &report.path)?;
+        }
+        for errata in &self.errata {
+            db::add_unsent(db_conn, series, ERRATA, &errata.path)?;
+        }
+        Ok(())
+    }
+
+    /// Everything generated since the last successful send, read back from the build directory
+    pub fn load_unsent(
+        db_conn: &Connection,
+        series: &str,
+    ) -> Result<GeneratedEpubs, Box<dyn std::error::Error>> {
+        let mut unsent = GeneratedEpubs::default();
+        for (kind, path) in db::get_unsent(db_conn, series)? {
+            let bytes = match std::fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("Not sending {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let is_html = path
+                .extension()
+                .is_some_and(|extension| extension == "html");
+            let attachment = Attachment {
+                filename: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                mime: String::from(if is_html {
+                    "text/html"
+                } else {
+                    "application/epub+zip"
+                }),
+                bytes,
+                path,
+            };
+            match kind.as_str() {
+                DIFF_REPORT => unsent.diff_reports.push(attachment),
+                ERRATA => unsent.errata.push(attachment),
+                _ => {
+                    if let Some(variant) = Variant::ALL.iter().copied().find(|v| v.key() == kind) {
+                        unsent.get_mut(variant).push(attachment);
+                    }
+                }
+            }
+        }
+        Ok(unsent)
+    }
+}
+
+pub fn generate_epubs(
     db_conn: &Connection,
+    series: &str,
+    info: &SeriesInfo,
     build_dir: &Path,
-    config: &config::Config,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut vols = Vec::<Attachment>::new();
-    let mut vols_stripped = Vec::<Attachment>::new();
-    let mut chaps = Vec::<Attachment>::new();
-    let mut chaps_stripped = Vec::<Attachment>::new();
+    plan: &BuildPlan,
+) -> Result<GeneratedEpubs, Box<dyn std::error::Error>> {
+    let mut generated = GeneratedEpubs::default();
 
-    if config.epub_gen.volumes {
-        let volumes = db::get_volumes_to_regenerate(db_conn)?;
+    if plan.generates(Variant::Volumes) || plan.generates(Variant::VolumesStripped) {
+        let volumes = db::get_volumes_to_regenerate(db_conn, series)?;
 
         if volumes.is_empty() {
             println!("No volumes to generate");
@@ -289,51 +511,87 @@ pub async fn generate_epubs(
         }
 
         for volume in volumes {
-            println!("Generating epub for {}", volume.name);
             let chapters = db::get_chapters_by_volume(db_conn, volume.id)?;
-            if config.epub_gen.strip_colour {
-                vols.push(generate_volume(
+            // every chapter was moved out or removed from the table of contents
+            if chapters.is_empty() {
+                println!("Skipping {}, it has no chapters", volume.name);
+                db::update_generated_volume(db_conn, volume.id, false)?;
+                continue;
+            }
+            // the volume stays flagged and is built once every chapter in it has downloaded
+            let missing: Vec<&str> = chapters
+                .iter()
+                .filter(|chapter| chapter.data_id == 0)
+                .map(|chapter| chapter.name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                println!(
+                    "Skipping {} until these chapters download: {}",
+                    volume.name,
+                    missing.join(", ")
+                );
+                continue;
+            }
+            println!("Generating epub for {}", volume.name);
+            if plan.generates(Variant::VolumesStripped) {
+                let epub = generate_volume(
                     db_conn,
+                    info,
                     &volume,
                     &chapters,
                     &build_dir.join("volumes_stripped_colour"),
                     true,
-                )?);
+                )?;
+                generated.add(db_conn, series, Variant::VolumesStripped, epub)?;
+            }
+            if plan.generates(Variant::Volumes) {
+                let epub = generate_volume(
+                    db_conn,
+                    info,
+                    &volume,
+                    &chapters,
+                    &build_dir.join("volumes"),
+                    false,
+                )?;
+                generated.add(db_conn, series, Variant::Volumes, epub)?;
             }
-            vols_stripped.push(generate_volume(
-                db_conn,
-                &volume,
-                &chapters,
-                &build_dir.join("volumes"),
-                false,
-            )?);
             db::update_generated_volume(db_conn, volume.id, false)?;
         }
     } else {
         println!("Skipping volume generation");
     }
 
-    if config.epub_gen.chapters {
-        let chapters = db::get_chapters_to_regenerate(db_conn)?;
+    if plan.generates(Variant::Chapters) || plan.generates(Variant::ChaptersStripped) {
+        let chapters = db::get_chapters_to_regenerate(db_conn, series)?;
         if chapters.is_empty() {
             println!("No chapters to generate");
-            return Ok(());
-        }
-        println!("Generating epubs for {} chapters", chapters.len());
-        if config.epub_gen.strip_colour {
-            chaps_stripped = generate_chapters(
-                db_conn,
-                &chapters,
-                &build_dir.join("chapters_stripped_colour"),
-                true,
-            )?;
+        } else {
+            println!("Generating epubs for {} chapters", chapters.len());
+            if plan.generates(Variant::ChaptersStripped) {
+                for epub in generate_chapters(
+                    db_conn,
+                    info,
+                    &chapters,
+                    &build_dir.join("chapters_stripped_colour"),
+                    true,
+                )? {
+                    generated.add(db_conn, series, Variant::ChaptersStripped, epub)?;
+                }
+            }
+            if plan.generates(Variant::Chapters) {
+                for epub in
+                    generate_chapters(db_conn, info, &chapters, &build_dir.join("chapters"), false)?
+                {
+                    generated.add(db_conn, series, Variant::Chapters, epub)?;
+                }
+            }
+            for chapter in &chapters {
+                db::update_generated_chapter(db_conn, chapter.id, false)?;
+            }
         }
-        chaps = generate_chapters(db_conn, &chapters, &build_dir.join("chapters"), false)?;
     } else {
         println!("Skipping chapter generation");
     }
 
-    send_epubs(&config.mail, &vols, &vols_stripped, &chaps, &chaps_stripped).await;
-
-    Ok(())
+    Ok(generated)
 }
@@ -0,0 +1,186 @@
+use regex::Regex;
+use soup::prelude::*;
+use std::fmt;
+
+use crate::config::SelectorsConfig;
+
+/// An element selector in the form `tag`, `.class` or `tag.class`, which is all soup can query
+pub struct Selector {
+    source: String,
+    tag: Option<String>,
+    class: Option<String>,
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Selector, String> {
+        let (tag, class) = match source.split_once('.') {
+            Some((tag, class)) => (tag, Some(class)),
+            None => (source, None),
+        };
+        let valid = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        // the tag can only be left out when there's a class
+        let tag_valid = valid(tag) || tag.is_empty() && class.is_some();
+        if !tag_valid || !class.is_none_or(valid) {
+            return Err(format!(
+                "<{}> is not a supported selector, use tag, .class or tag.class",
+                source
+            ));
+        }
+        Ok(Selector {
+            source: source.to_string(),
+            tag: Some(tag.to_string()).filter(|tag| !tag.is_empty()),
+            class: class.map(String::from),
+        })
+    }
+
+    pub fn find_all<'a, Q: QueryBuilderExt>(
+        &'a self,
+        node: &Q,
+    ) -> impl Iterator<Item = impl NodeExt + QueryBuilderExt> + 'a {
+        match (&self.tag, &self.class) {
+            (Some(tag), Some(class)) => node.tag(tag.as_str()).class(class.as_str()).find_all(),
+            (Some(tag), None) => node.tag(tag.as_str()).find_all(),
+            (None, Some(class)) => node.class(class.as_str()).find_all(),
+            (None, None) => unreachable!("parse requires a tag or a class"),
+        }
+    }
+
+    pub fn find<Q: QueryBuilderExt>(&self, node: &Q) -> Option<impl NodeExt + QueryBuilderExt> {
+        self.find_all(node).next()
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The parts of the site's pages the scraper reads, built from the Selectors config section
+pub struct Layout {
+    pub volume: Selector,
+    pub volume_title: Selector,
+    pub chapter_entry: Selector,
+    pub chapter_link: Selector,
+    pub entry_content: Selector,
+    pub entry_title: Selector,
+    pub password_form: Selector,
+    early_access: Regex,
+}
+
+impl Layout {
+    /// Returns every selector that couldn't be parsed, named by its config path
+    pub fn new(config: &SelectorsConfig) -> Result<Layout, Vec<String>> {
+        let mut problems = Vec::<String>::new();
+        let mut selector = |name: &str, source: &str| {
+            Selector::parse(source)
+                .map_err(|e| problems.push(format!("Selectors.{}: {}", name, e)))
+                .ok()
+        };
+        let volume = selector("VolumeWrapper", &config.volume_wrapper);
+        let volume_title = selector("VolumeTitle", &config.volume_title);
+        let chapter_entry = selector("ChapterEntry", &config.chapter_entry);
+        let chapter_link = selector("ChapterLink", &config.chapter_link);
+        let entry_content = selector("EntryContent", &config.entry_content);
+        let entry_title = selector("EntryTitle", &config.entry_title);
+        let password_form = selector("PasswordForm", &config.password_form);
+        let early_access = Regex::new(&config.early_access_title)
+            .map_err(|e| {
+                problems.push(format!(
+                    "Selectors.EarlyAccessTitle is not a valid regex: {}",
+                    e
+                ))
+            })
+            .ok();
+
+        match (
+            volume,
+            volume_title,
+            chapter_entry,
+            chapter_link,
+            entry_content,
+            entry_title,
+            password_form,
+            early_access,
+        ) {
+            (
+                Some(volume),
+                Some(volume_title),
+                Some(chapter_entry),
+                Some(chapter_link),
+                Some(entry_content),
+                Some(entry_title),
+                Some(password_form),
+                Some(early_access),
+            ) => Ok(Layout {
+                volume,
+                volume_title,
+                chapter_entry,
+                chapter_link,
+                entry_content,
+                entry_title,
+                password_form,
+                early_access,
+            }),
+            _ => Err(problems),
+        }
+    }
+
+    pub fn is_early_access(&self, title: &str) -> bool {
+        self.early_access.is_match(title)
+    }
+}
+
+/// An element the scraper relies on is missing from a page
+#[derive(Debug)]
+pub struct LayoutError {
+    pub uri: String,
+    pub selector: String,
+    // which part of the page was being read
+    pub context: String,
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has no {} in {}, the site layout has probably changed, \
+            check the Selectors in the series' config section",
+            self.uri, self.selector, self.context
+        )
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tag_and_class() {
+        let selector = Selector::parse("div.entry-content").unwrap();
+        assert_eq!(selector.tag.as_deref(), Some("div"));
+        assert_eq!(selector.class.as_deref(), Some("entry-content"));
+
+        let selector = Selector::parse("h1").unwrap();
+        assert_eq!(selector.tag.as_deref(), Some("h1"));
+        assert_eq!(selector.class, None);
+
+        let selector = Selector::parse(".chapter_title").unwrap();
+        assert_eq!(selector.tag, None);
+        assert_eq!(selector.class.as_deref(), Some("chapter_title"));
+    }
+
+    #[test]
+    fn parse_rejects_unsupported_selectors() {
+        for source in ["", ".", "div.", "div p", "#content", "div.a.b", "a[href]"] {
+            assert!(Selector::parse(source).is_err(), "{:?}", source);
+        }
+    }
+}
@@ -1,11 +1,16 @@
-use crate::config::{MailConfig, UserConfig};
+use crate::config::MailConfig;
+use crate::epub::GeneratedEpubs;
+use crate::plan::{BuildPlan, Delivery};
 use mail_builder::MessageBuilder;
 use mail_send::SmtpClientBuilder;
+use std::path::PathBuf;
 
 pub struct Attachment {
     pub filename: String,
     pub mime: String,
     pub bytes: Vec<u8>,
+    // where the file was written in the build directory
+    pub path: PathBuf,
 }
 impl Default for Attachment {
     fn default() -> Self {
@@ -13,11 +18,16 @@ impl Default for Attachment {
             filename: String::default(),
             mime: String::from("application/epub+zip"),
             bytes: Vec::<u8>::default(),
+            path: PathBuf::default(),
         }
     }
 }
 
-async fn send_epub(config: &MailConfig, dest: &UserConfig, attachment: &Attachment) {
+async fn send_epub(
+    config: &MailConfig,
+    dest: &Delivery,
+    attachment: &Attachment,
+) -> Result<(), mail_send::Error> {
     let message = MessageBuilder::new()
         .from((config.name.clone(), config.address.clone()))
         .to(vec![(dest.name.clone(), dest.email.clone())])
@@ -27,52 +37,39 @@ async fn send_epub(config: &MailConfig, dest: &UserConfig, attachment: &Attachme
             attachment.bytes.clone(),
         );
 
-    let res = SmtpClientBuilder::new(config.smtp_hostname.clone(), config.smtp_port)
+    SmtpClientBuilder::new(config.smtp_hostname.clone(), config.smtp_port)
         .implicit_tls(false)
         .credentials((config.address.clone(), config.password.clone()))
         .connect()
-        .await
-        .unwrap()
+        .await?
         .send(message)
-        .await;
+        .await?;
 
-    match res {
-        Ok(()) => println!(
-            "Sent Chapter {} to {}",
-            attachment.filename.clone(),
-            dest.email,
-        ),
-        Err(error) => panic!("Problem with sending email {:?}", error),
-    };
+    println!("Sent {} to {}", attachment.filename, dest.email);
+    Ok(())
 }
 
 pub async fn send_epubs(
     config: &MailConfig,
-    volumes: &Vec<Attachment>,
-    volumes_s: &Vec<Attachment>,
-    chapters: &Vec<Attachment>,
-    chapters_s: &Vec<Attachment>,
-) {
-    for dest in config.destinations.clone() {
-        if dest.send_full_volumes {
-            for vol in volumes {
-                send_epub(config, &dest, vol).await;
-            }
-            if dest.strip_colour {
-                for vol in volumes_s {
-                    send_epub(config, &dest, vol).await;
-                }
+    plan: &BuildPlan,
+    epubs: &GeneratedEpubs,
+) -> Result<(), mail_send::Error> {
+    for dest in &plan.deliveries {
+        for variant in &dest.variants {
+            for attachment in epubs.get(*variant) {
+                send_epub(config, dest, attachment).await?;
             }
         }
-        if dest.send_individual_chapters {
-            for chapter in chapters {
-                send_epub(config, &dest, chapter).await;
+        if dest.diff_report {
+            for report in &epubs.diff_reports {
+                send_epub(config, dest, report).await?;
             }
-            if dest.strip_colour {
-                for chapter in chapters_s {
-                    send_epub(config, &dest, chapter).await;
-                }
+        }
+        if dest.errata {
+            for errata in &epubs.errata {
+                send_epub(config, dest, errata).await?;
             }
         }
     }
+    Ok(())
 }
@@ -1,46 +1,489 @@
-use std::path::Path;
+use clap::Parser;
+use rusqlite::Connection;
+use std::process::ExitCode;
+use std::time::{Duration, UNIX_EPOCH};
 
+mod cli;
 mod config;
+mod cookies;
 mod db;
+mod diff;
 mod epub;
+mod layout;
 mod mail;
+mod paths;
+mod plan;
+mod ratelimit;
 mod scraper;
+mod site;
+mod wandering_inn;
 
-#[tokio::main]
-async fn main() {
-    let config = config::load_config();
+use cli::{Cli, Command, ConfigCommand, FailuresCommand, RevisionsCommand};
+use paths::Paths;
+use plan::BuildPlan;
+use scraper::Fetcher;
+use site::SiteAdapter;
+
+/// Pipeline step that failed, used to pick the process exit code
+#[derive(Clone, Copy, Debug)]
+enum Stage {
+    Config,
+    Database,
+    Client,
+    Index,
+    Download,
+    Reprocess,
+    Build,
+    Mail,
+}
+
+impl Stage {
+    fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Stage::Config => 2,
+            Stage::Database => 3,
+            Stage::Client => 4,
+            Stage::Index => 5,
+            Stage::Download => 6,
+            Stage::Build => 7,
+            Stage::Mail => 8,
+            Stage::Reprocess => 9,
+        })
+    }
 
-    let conn = match db::open() {
-        Ok(conn) => conn,
-        Err(e) => {
-            panic!("Error opening database: {}", e);
+    fn description(self) -> &'static str {
+        match self {
+            Stage::Config => "loading config",
+            Stage::Database => "accessing database",
+            Stage::Client => "building request client",
+            Stage::Index => "updating index",
+            Stage::Download => "getting chapters",
+            Stage::Reprocess => "reprocessing chapters",
+            Stage::Build => "generating epubs",
+            Stage::Mail => "sending epubs",
         }
-    };
+    }
+}
+
+struct Failure {
+    stage: Stage,
+    error: Box<dyn std::error::Error>,
+}
+
+trait StageContext<T> {
+    fn stage(self, stage: Stage) -> Result<T, Failure>;
+}
+
+impl<T, E: Into<Box<dyn std::error::Error>>> StageContext<T> for Result<T, E> {
+    fn stage(self, stage: Stage) -> Result<T, Failure> {
+        self.map_err(|e| Failure {
+            stage,
+            error: e.into(),
+        })
+    }
+}
+
+async fn client(
+    config: &config::Config,
+    paths: &Paths,
+    site: &dyn SiteAdapter,
+) -> Result<Fetcher, Failure> {
+    scraper::build_client(config, &paths.cookies(site.series()), site)
+        .await
+        .stage(Stage::Client)
+}
+
+async fn index(
+    conn: &Connection,
+    client: &Fetcher,
+    site: &dyn SiteAdapter,
+    force: bool,
+) -> Result<(), Failure> {
+    scraper::update_index(conn, client, site, force)
+        .await
+        .stage(Stage::Index)
+}
+
+async fn download(
+    conn: &Connection,
+    config: &config::Config,
+    client: &Fetcher,
+    site: &dyn SiteAdapter,
+) -> Result<(), Failure> {
+    let summary = scraper::download_all_chapters(
+        conn,
+        client,
+        site,
+        config.patreon_prompt,
+        config.concurrent_downloads,
+    )
+    .await
+    .stage(Stage::Download)?;
+    downloaded(summary)
+}
 
-    let client = match scraper::build_client(config.patreon_prompt).await {
-        Ok(client) => client,
-        Err(e) => panic!("Error building request client: {}", e),
-    };
+fn downloaded(summary: scraper::DownloadSummary) -> Result<(), Failure> {
+    if summary.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} chapters failed to download",
+            summary.failed.len()
+        ))
+        .stage(Stage::Download)
+    }
+}
 
-    match scraper::update_index(&conn, &config.toc_url, &client).await {
-        Ok(_) => (),
-        Err(e) => panic!("Error updating index: {}", e),
+fn list_failures(conn: &Connection, series: &str) -> Result<(), Failure> {
+    let attempts = db::get_download_attempts(conn, series).stage(Stage::Database)?;
+    if attempts.is_empty() {
+        println!("No failed downloads");
+    }
+    for attempt in attempts {
+        let last_try = UNIX_EPOCH + Duration::from_secs(attempt.last_try);
+        println!(
+            "{} ({} attempts, last tried {}): {}",
+            attempt.chapter_name,
+            attempt.attempts,
+            httpdate::fmt_http_date(last_try),
+            attempt
+                .last_error
+                .as_deref()
+                .unwrap_or("did not finish, the run was interrupted")
+        );
     }
+    Ok(())
+}
 
-    match scraper::download_all_chapters(
-        &conn,
-        &config.request_delay,
+// Downloads the chosen failed chapters straight away, ignoring their backoff
+async fn retry_failures(
+    conn: &Connection,
+    config: &config::Config,
+    paths: &Paths,
+    site: &dyn SiteAdapter,
+    chapters: Vec<String>,
+) -> Result<(), Failure> {
+    let attempts = db::get_download_attempts(conn, site.series()).stage(Stage::Database)?;
+    for name in &chapters {
+        if !attempts.iter().any(|attempt| &attempt.chapter_name == name) {
+            println!("{} has not failed to download", name);
+        }
+    }
+    let ids: Vec<usize> = attempts
+        .iter()
+        .filter(|attempt| chapters.is_empty() || chapters.contains(&attempt.chapter_name))
+        .map(|attempt| attempt.chapter_id)
+        .collect();
+    let selected = db::get_chapters_to_download(conn, site.series())
+        .stage(Stage::Database)?
+        .into_iter()
+        .filter(|chapter| ids.contains(&chapter.id))
+        .collect();
+
+    let summary = scraper::download_chapters(
+        conn,
+        &client(config, paths, site).await?,
+        site,
+        selected,
         config.patreon_prompt,
-        &client,
+        config.concurrent_downloads,
     )
     .await
-    {
-        Ok(_) => (),
-        Err(e) => panic!("Error getting chapters: {}", e),
+    .stage(Stage::Download)?;
+    downloaded(summary)
+}
+
+fn list_revisions(conn: &Connection, series: &str, name: &str) -> Result<(), Failure> {
+    let chapters = db::get_chapters_by_name(conn, series, name).stage(Stage::Database)?;
+    if chapters.is_empty() {
+        return Err(format!("There is no chapter named {}", name)).stage(Stage::Database);
+    }
+    for chapter in chapters {
+        println!("{} ({})", chapter.name, chapter.uri);
+        let revisions = db::get_revisions(conn, chapter.id).stage(Stage::Database)?;
+        if revisions.is_empty() {
+            println!("  not downloaded yet");
+        }
+        for (i, revision) in revisions.iter().enumerate() {
+            println!(
+                "  {}: fetched {}, hash {}{}",
+                revision.id,
+                diff::fetched(revision.fetched_at),
+                &revision.hash[..12],
+                if i == 0 { " (current)" } else { "" }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn diff_chapter(
+    conn: &Connection,
+    series: &str,
+    name: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<(), Failure> {
+    let chapters = db::get_chapters_by_name(conn, series, name).stage(Stage::Database)?;
+    if chapters.is_empty() {
+        return Err(format!("There is no chapter named {}", name)).stage(Stage::Database);
     }
+    for chapter in chapters {
+        // newest first, so the revision before another is the next one in the list
+        let revisions = db::get_revisions(conn, chapter.id).stage(Stage::Database)?;
+        let position = |id: Option<usize>, default: usize| match id {
+            Some(id) => revisions
+                .iter()
+                .position(|revision| revision.id == id)
+                .ok_or_else(|| format!("Revision {} is not a revision of {}", id, chapter.name)),
+            None => Ok(default),
+        };
+        let to = position(to, 0).stage(Stage::Database)?;
+        let from = position(from, to + 1).stage(Stage::Database)?;
+        match (revisions.get(from), revisions.get(to)) {
+            (Some(old), Some(new)) => println!("{}", diff::diff_revisions(&chapter.name, old, new)),
+            _ => println!("{} has no earlier revision to compare with", chapter.name),
+        }
+    }
+    Ok(())
+}
+
+fn build_revision(
+    conn: &Connection,
+    paths: &Paths,
+    site: &dyn SiteAdapter,
+    revision_id: usize,
+) -> Result<(), Failure> {
+    let revision = db::get_revision(conn, revision_id)
+        .stage(Stage::Database)?
+        .ok_or_else(|| format!("There is no revision {}", revision_id))
+        .stage(Stage::Database)?;
+    let chapter = db::get_chapter(conn, revision.chapter_id).stage(Stage::Database)?;
+    let path = epub::generate_revision(
+        site.info(),
+        &chapter,
+        &revision,
+        &paths.series_build_dir(site.series()),
+    )
+    .stage(Stage::Build)?;
+    println!("Generated {}", path.display());
+    Ok(())
+}
+
+fn build(
+    conn: &Connection,
+    config: &config::Config,
+    paths: &Paths,
+    site: &dyn SiteAdapter,
+) -> Result<(), Failure> {
+    let build_dir = paths.series_build_dir(site.series());
+    let mut epubs = epub::generate_epubs(
+        conn,
+        site.series(),
+        site.info(),
+        &build_dir,
+        &BuildPlan::new(config),
+    )
+    .stage(Stage::Build)?;
+
+    // every edit since the last build goes in one report and errata, then isn't reported again
+    let edits = diff::unreported_edits(conn, site.series()).stage(Stage::Build)?;
+    if !edits.is_empty() {
+        let report = diff::write_report(&edits, site.info(), &build_dir).stage(Stage::Build)?;
+        println!(
+            "Wrote a report of {} edits to {}",
+            edits.len(),
+            report.path.display()
+        );
+        epubs.diff_reports.push(report);
+        if let Some(errata) =
+            epub::generate_errata(site.info(), &edits, &build_dir).stage(Stage::Build)?
+        {
+            println!("Generated {}", errata.path.display());
+            epubs.errata.push(errata);
+        }
+    }
+    // the report is only marked as done once it's recorded for the next send
+    epubs
+        .record_unsent(conn, site.series())
+        .stage(Stage::Database)?;
+    if !edits.is_empty() {
+        db::mark_revisions_reported(conn, site.series()).stage(Stage::Database)?;
+    }
+    Ok(())
+}
+
+// Sends everything built since the last successful send, including earlier `build` runs
+async fn send(
+    conn: &Connection,
+    config: &config::Config,
+    site: &dyn SiteAdapter,
+) -> Result<(), Failure> {
+    if config.mail.destinations.is_empty() {
+        println!("No destinations configured, not sending any epubs");
+        return db::clear_unsent(conn, site.series()).stage(Stage::Database);
+    }
+    let epubs = epub::GeneratedEpubs::load_unsent(conn, site.series()).stage(Stage::Database)?;
+    mail::send_epubs(&config.mail, &BuildPlan::new(config), &epubs)
+        .await
+        .stage(Stage::Mail)?;
+    db::clear_unsent(conn, site.series()).stage(Stage::Database)
+}
+
+fn status(conn: &Connection, paths: &Paths, series: &str) -> Result<(), Failure> {
+    let status = db::get_status(conn, series).stage(Stage::Database)?;
+    println!("Series: {}", series);
+    println!("Database: {}", paths.database.display());
+    println!(
+        "Build directory: {}",
+        paths.series_build_dir(series).display()
+    );
+    println!("Cookies: {}", paths.cookies(series).display());
+    println!("Volumes indexed: {}", status.volumes);
+    println!(
+        "Chapters downloaded: {}/{}",
+        status.downloaded_chapters, status.chapters
+    );
+    println!(
+        "Chapters removed from the table of contents: {}",
+        status.removed_chapters
+    );
+    println!(
+        "Patron early access chapters: {}",
+        status.early_access_chapters
+    );
+    println!(
+        "Pending epubs: {} volumes, {} chapters",
+        status.volumes_to_regenerate, status.chapters_to_regenerate
+    );
+    println!("Failed downloads: {}", status.failed_downloads);
+    Ok(())
+}
+
+fn open_db(paths: &Paths) -> Result<Connection, Failure> {
+    db::open(&paths.database).stage(Stage::Database)
+}
+
+async fn run(command: Command, paths: &Paths) -> Result<(), Failure> {
+    let config = &config::load_config(&paths.config).stage(Stage::Config)?;
+    let site = &*site::adapter(config)
+        .map_err(|problems| problems.join(", "))
+        .stage(Stage::Config)?;
 
-    match epub::generate_epubs(&conn, Path::new("build/"), &config).await {
-        Ok(_) => (),
-        Err(e) => panic!("Error generating epubs: {}", e),
+    match command {
+        Command::Index { force } => {
+            let conn = open_db(paths)?;
+            index(&conn, &client(config, paths, site).await?, site, force).await
+        }
+        Command::Download => {
+            let conn = open_db(paths)?;
+            download(&conn, config, &client(config, paths, site).await?, site).await
+        }
+        Command::Refresh { volume, days, .. } => {
+            // clap makes sure exactly one of --all, --volume and --days is given
+            let conn = open_db(paths)?;
+            let selection = match (volume, days) {
+                (Some(volume), _) => {
+                    let volumes = db::get_volumes(&conn, site.series()).stage(Stage::Database)?;
+                    if !volumes.iter().any(|v| v.name == volume) {
+                        return Err(format!("There is no volume named {}", volume))
+                            .stage(Stage::Database);
+                    }
+                    db::ChapterSelection::Volume(volume)
+                }
+                (_, Some(days)) => db::ChapterSelection::RecentDays(days),
+                _ => db::ChapterSelection::All,
+            };
+            let count =
+                db::refetch_chapters(&conn, site.series(), &selection).stage(Stage::Database)?;
+            println!("Rechecking {} chapters", count);
+            download(&conn, config, &client(config, paths, site).await?, site).await
+        }
+        Command::Reprocess => {
+            scraper::reprocess_chapters(&open_db(paths)?, site).stage(Stage::Reprocess)
+        }
+        Command::Build => build(&open_db(paths)?, config, paths, site),
+        Command::Send => {
+            let conn = open_db(paths)?;
+            build(&conn, config, paths, site)?;
+            send(&conn, config, site).await
+        }
+        Command::Status => status(&open_db(paths)?, paths, site.series()),
+        Command::Failures { command } => {
+            let conn = open_db(paths)?;
+            match command {
+                FailuresCommand::List => list_failures(&conn, site.series()),
+                // clap makes sure either --all or some chapters are given
+                FailuresCommand::Retry { chapters, .. } => {
+                    retry_failures(&conn, config, paths, site, chapters).await
+                }
+            }
+        }
+        Command::Revisions { command } => {
+            let conn = open_db(paths)?;
+            match command {
+                RevisionsCommand::List { chapter } => {
+                    list_revisions(&conn, site.series(), &chapter)
+                }
+                RevisionsCommand::Build { revision } => {
+                    build_revision(&conn, paths, site, revision)
+                }
+            }
+        }
+        Command::Diff { chapter, from, to } => {
+            diff_chapter(&open_db(paths)?, site.series(), &chapter, from, to)
+        }
+        Command::ClearCookies => {
+            let cookies = paths.cookies(site.series());
+            if cookies::clear(&cookies).stage(Stage::Database)? {
+                println!("Deleted {}", cookies.display());
+            } else {
+                println!("No saved cookies at {}", cookies.display());
+            }
+            Ok(())
+        }
+        Command::Plan => {
+            println!("{}", BuildPlan::new(config));
+            Ok(())
+        }
+        Command::Run => {
+            let conn = open_db(paths)?;
+            let client = client(config, paths, site).await?;
+            index(&conn, &client, site, false).await?;
+            // still build and send whatever did download, but report the failures afterwards
+            let downloaded = download(&conn, config, &client, site).await;
+            build(&conn, config, paths, site)?;
+            send(&conn, config, site).await?;
+            downloaded
+        }
+        Command::Config { command } => match command {
+            ConfigCommand::Check => {
+                // the defaults always validate, so a missing file would otherwise look fine
+                if !paths.config.exists() {
+                    return Err(format!("{} does not exist", paths.config.display()))
+                        .stage(Stage::Config);
+                }
+                println!("{} is valid", paths.config.display());
+                Ok(())
+            }
+            ConfigCommand::Show => {
+                println!("{:#}", config.masked());
+                Ok(())
+            }
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let paths = Paths::resolve(&cli);
+
+    match run(cli.command.unwrap_or(Command::Run), &paths).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("Error {}: {}", failure.stage.description(), failure.error);
+            failure.stage.exit_code()
+        }
     }
 }
@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+
+use crate::cli::Cli;
+use crate::wandering_inn::WanderingInn;
+
+const APP_DIR: &str = "wandering_inn_scraper";
+
+pub struct Paths {
+    pub config: PathBuf,
+    pub database: PathBuf,
+    pub build_dir: PathBuf,
+}
+
+// Prefer the old working directory layout if it's already in use so existing setups keep working,
+// otherwise fall back to the platform specific (XDG on linux) directories
+fn default_path(local: &str, base: Option<PathBuf>, name: &str) -> PathBuf {
+    let local = Path::new(local);
+    if local.exists() {
+        return local.to_path_buf();
+    }
+    match base {
+        Some(base) => base.join(APP_DIR).join(name),
+        None => local.to_path_buf(),
+    }
+}
+
+// Same as default_path but accepts either config.json or config.jsonc in each location
+fn default_config_path() -> PathBuf {
+    let names = ["config.json", "config.jsonc"];
+    let config_dir = dirs::config_dir().map(|dir| dir.join(APP_DIR));
+
+    let candidates = names.iter().map(PathBuf::from).chain(
+        config_dir
+            .iter()
+            .flat_map(|dir| names.iter().map(move |name| dir.join(name))),
+    );
+    for candidate in candidates {
+        if candidate.exists() {
+            return candidate;
+        }
+    }
+    default_path("config.json", dirs::config_dir(), "config.json")
+}
+
+impl Paths {
+    /// Where a series' epubs are built, the wandering inn keeps the top level so existing
+    /// build directories carry on where they left off
+    pub fn series_build_dir(&self, series: &str) -> PathBuf {
+        if series == WanderingInn::SERIES {
+            self.build_dir.clone()
+        } else {
+            self.build_dir.join(series)
+        }
+    }
+
+    /// Where a series' cookies are saved, next to the database and named after it so separate
+    /// instances keep separate sessions even when their databases share a directory
+    pub fn cookies(&self, series: &str) -> PathBuf {
+        let stem = self
+            .database
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        self.database
+            .with_file_name(if series == WanderingInn::SERIES {
+                format!("{}-cookies.json", stem)
+            } else {
+                format!("{}-{}-cookies.json", stem, series)
+            })
+    }
+
+    pub fn resolve(cli: &Cli) -> Paths {
+        Paths {
+            config: cli.config.clone().unwrap_or_else(default_config_path),
+            database: cli
+                .db
+                .clone()
+                .unwrap_or_else(|| default_path("db/index.db", dirs::data_dir(), "index.db")),
+            build_dir: cli
+                .build_dir
+                .clone()
+                .unwrap_or_else(|| default_path("build", dirs::data_dir(), "build")),
+        }
+    }
+}
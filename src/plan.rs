@@ -0,0 +1,223 @@
+use std::fmt;
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Volumes,
+    VolumesStripped,
+    Chapters,
+    ChaptersStripped,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 4] = [
+        Variant::Volumes,
+        Variant::VolumesStripped,
+        Variant::Chapters,
+        Variant::ChaptersStripped,
+    ];
+
+    /// Stable name the variant is stored under in the database
+    pub fn key(self) -> &'static str {
+        match self {
+            Variant::Volumes => "volumes",
+            Variant::VolumesStripped => "volumes_stripped",
+            Variant::Chapters => "chapters",
+            Variant::ChaptersStripped => "chapters_stripped",
+        }
+    }
+
+    pub fn is_volume(self) -> bool {
+        matches!(self, Variant::Volumes | Variant::VolumesStripped)
+    }
+
+    pub fn is_stripped(self) -> bool {
+        matches!(self, Variant::VolumesStripped | Variant::ChaptersStripped)
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Variant::Volumes => "volumes",
+            Variant::VolumesStripped => "volumes (colour stripped)",
+            Variant::Chapters => "chapters",
+            Variant::ChaptersStripped => "chapters (colour stripped)",
+        })
+    }
+}
+
+pub struct Delivery {
+    pub name: String,
+    pub email: String,
+    pub variants: Vec<Variant>,
+    pub diff_report: bool,
+    pub errata: bool,
+}
+
+/// The epub variants that will be generated and who receives which, computed from
+/// EpubGen and the mail destinations without changing either
+pub struct BuildPlan {
+    // every variant alongside the reasons it is generated, no reasons means it is skipped
+    pub variants: Vec<(Variant, Vec<String>)>,
+    pub deliveries: Vec<Delivery>,
+}
+
+impl BuildPlan {
+    pub fn new(config: &Config) -> BuildPlan {
+        let epub_gen = &config.epub_gen;
+
+        let deliveries: Vec<Delivery> = config
+            .mail
+            .destinations
+            .iter()
+            .map(|dest| Delivery {
+                name: dest.name.clone(),
+                email: dest.email.clone(),
+                variants: Variant::ALL
+                    .iter()
+                    .copied()
+                    // stripped destinations get the stripped epubs on top of the normal ones
+                    .filter(|variant| {
+                        (!variant.is_stripped() || dest.strip_colour)
+                            && if variant.is_volume() {
+                                dest.send_full_volumes
+                            } else {
+                                dest.send_individual_chapters
+                            }
+                    })
+                    .collect(),
+                diff_report: dest.send_diff_report,
+                errata: dest.send_errata,
+            })
+            .collect();
+
+        let variants = Variant::ALL
+            .iter()
+            .copied()
+            .map(|variant| {
+                let mut reasons = Vec::<String>::new();
+                let wanted = if variant.is_volume() {
+                    epub_gen.volumes
+                } else {
+                    epub_gen.chapters
+                };
+                if wanted && (!variant.is_stripped() || epub_gen.strip_colour) {
+                    reasons.push(String::from("EpubGen"));
+                }
+                for delivery in &deliveries {
+                    if delivery.variants.contains(&variant) {
+                        reasons.push(format!("<{}>", delivery.email));
+                    }
+                }
+                (variant, reasons)
+            })
+            .collect();
+
+        BuildPlan {
+            variants,
+            deliveries,
+        }
+    }
+
+    pub fn generates(&self, variant: Variant) -> bool {
+        self.variants
+            .iter()
+            .any(|(v, reasons)| *v == variant && !reasons.is_empty())
+    }
+}
+
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Generating:")?;
+        for (variant, reasons) in &self.variants {
+            if reasons.is_empty() {
+                writeln!(f, "  {}: no", variant)?;
+            } else {
+                writeln!(f, "  {}: yes, for {}", variant, reasons.join(", "))?;
+            }
+        }
+
+        if self.deliveries.is_empty() {
+            write!(f, "No destinations configured")?;
+        } else {
+            write!(f, "Sending:")?;
+        }
+        for delivery in &self.deliveries {
+            let mut variants: Vec<String> =
+                delivery.variants.iter().map(Variant::to_string).collect();
+            if delivery.diff_report {
+                variants.push(String::from("diff report"));
+            }
+            if delivery.errata {
+                variants.push(String::from("errata"));
+            }
+            write!(
+                f,
+                "\n  <{}> at <{}>: {}",
+                delivery.name,
+                delivery.email,
+                if variants.is_empty() {
+                    String::from("nothing")
+                } else {
+                    variants.join(", ")
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+
+    fn destination(email: &str, strip_colour: bool, chapters: bool) -> UserConfig {
+        UserConfig {
+            email: String::from(email),
+            strip_colour,
+            send_individual_chapters: chapters,
+            ..UserConfig::default()
+        }
+    }
+
+    #[test]
+    fn variant_kinds() {
+        assert!(Variant::Volumes.is_volume() && !Variant::Volumes.is_stripped());
+        assert!(Variant::VolumesStripped.is_volume() && Variant::VolumesStripped.is_stripped());
+        assert!(!Variant::Chapters.is_volume() && !Variant::Chapters.is_stripped());
+        assert!(!Variant::ChaptersStripped.is_volume() && Variant::ChaptersStripped.is_stripped());
+    }
+
+    #[test]
+    fn stripped_destinations_get_both_versions() {
+        let mut config = Config::default();
+        config.mail.destinations = vec![
+            destination("plain@example.com", false, false),
+            destination("kindle@example.com", true, true),
+        ];
+        let plan = BuildPlan::new(&config);
+        assert_eq!(plan.deliveries[0].variants, vec![Variant::Volumes]);
+        assert_eq!(plan.deliveries[1].variants, Variant::ALL.to_vec());
+    }
+
+    #[test]
+    fn variants_are_generated_for_epub_gen_or_a_destination() {
+        let mut config = Config::default();
+        config.epub_gen.chapters = false;
+        assert!(BuildPlan::new(&config).generates(Variant::Volumes));
+        assert!(!BuildPlan::new(&config).generates(Variant::Chapters));
+        assert!(!BuildPlan::new(&config).generates(Variant::VolumesStripped));
+
+        config.mail.destinations = vec![destination("kindle@example.com", true, true)];
+        let plan = BuildPlan::new(&config);
+        assert!(plan.generates(Variant::Chapters));
+        assert!(plan.generates(Variant::VolumesStripped));
+        assert_eq!(
+            plan.variants[1].1,
+            vec![String::from("<kindle@example.com>")]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+use tokio::{
+    sync::Mutex,
+    time::{sleep_until, Instant},
+};
+
+/// Token bucket holding a single token that refills once per interval, so requests from any
+/// number of concurrent downloads are never started closer together than the interval
+pub struct RateLimiter {
+    interval: Duration,
+    // when the next token becomes available
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    // The interval is RequestDelay, or longer if the requests per minute cap asks for it
+    pub fn new(request_delay: Duration, requests_per_minute: Option<u32>) -> RateLimiter {
+        let interval = match requests_per_minute {
+            Some(per_minute) if per_minute > 0 => {
+                request_delay.max(Duration::from_secs(60) / per_minute)
+            }
+            _ => request_delay,
+        };
+        RateLimiter {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Waits for the next token without blocking the runtime, callers are served in order
+    pub async fn acquire(&self) {
+        let start = {
+            let mut next = self.next.lock().await;
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+        sleep_until(start).await;
+    }
+
+    /// Holds back every request that hasn't got a token yet until `delay` from now has passed
+    pub async fn pause(&self, delay: Duration) {
+        let mut next = self.next.lock().await;
+        *next = (*next).max(Instant::now() + delay);
+    }
+}
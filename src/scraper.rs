@@ -1,161 +1,993 @@
 use regex::Regex;
-use reqwest::{header::USER_AGENT, Client};
+use reqwest::{
+    cookie::CookieStore,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        RETRY_AFTER,
+    },
+    Client, Proxy, RequestBuilder, Response, StatusCode,
+};
 use rusqlite::{Connection, Result};
-use soup::prelude::*;
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use crate::config::Config;
+use crate::cookies;
 use crate::db;
+use crate::ratelimit::RateLimiter;
+use crate::site::{ChapterPage, PasswordLogin, SeriesInfo, SiteAdapter, TocEntry, TocVolume};
 
-use std::{thread, time::Duration};
+use futures_util::stream::{self, StreamExt};
+use rand::Rng;
+use reqwest_cookie_store::CookieStoreMutex;
+use std::time::{Duration, SystemTime};
 
-pub async fn build_client(parse_patreon: bool) -> Result<Client, Box<dyn std::error::Error>> {
-    let client = Client::builder().cookie_store(true).build()?;
+/// A chapter was still password protected after logging in, so the patreon password is wrong
+#[derive(Debug)]
+pub struct PatreonLoginError {
+    pub uri: String,
+}
+
+impl fmt::Display for PatreonLoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is still password protected, the patreon password was rejected \
+            (it may have been rotated, check the latest patreon post)",
+            self.uri
+        )
+    }
+}
+
+impl std::error::Error for PatreonLoginError {}
+
+// Configured password first, and only prompt if there's someone at a terminal to answer.
+// A prompted password is never written anywhere, the login cookie it gets is saved instead
+fn resolve_patreon_password(configured: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if !configured.is_empty() {
+        return Ok(configured.to_string());
+    }
+    if !stdin().is_terminal() {
+        return Err(
+            "PatreonPrompt is enabled but no patreon password is configured and there \
+            is no terminal to prompt on, set PatreonPassword, PatreonPasswordFile or \
+            WI_PATREON_PASSWORD"
+                .into(),
+        );
+    }
+
+    let mut password = String::new();
+    print!("Enter patreon chapter password: ");
+    stdout().flush()?;
+    stdin().read_line(&mut password)?;
+    Ok(password.trim().to_string())
+}
+
+/// The request client together with the rate limit and retry policy every request goes through
+pub struct Fetcher {
+    client: Client,
+    limiter: RateLimiter,
+    max_retries: u32,
+    cookies: Arc<CookieStoreMutex>,
+    cookie_path: PathBuf,
+}
+
+impl Fetcher {
+    /// Writes the cookie jar back to disk so the next run starts with the same session
+    pub fn save_cookies(&self) -> Result<(), Box<dyn std::error::Error>> {
+        cookies::save(&self.cookies, &self.cookie_path)
+    }
+
+    // the site remembers an entered chapter password with a cookie
+    fn has_login_cookie(&self, login: &PasswordLogin) -> bool {
+        self.cookies
+            .cookies(&login.url)
+            .and_then(|cookies| cookies.to_str().map(|c| c.contains(login.cookie)).ok())
+            .unwrap_or(false)
+    }
+}
+
+pub async fn build_client(
+    config: &Config,
+    cookie_path: &Path,
+    site: &dyn SiteAdapter,
+) -> Result<Fetcher, Box<dyn std::error::Error>> {
+    let http = &config.http;
+    let mut headers = HeaderMap::new();
+    for (name, value) in &http.headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    let jar = Arc::new(CookieStoreMutex::new(cookies::load(cookie_path)));
+    let mut builder = Client::builder()
+        .cookie_provider(jar.clone())
+        .user_agent(&http.user_agent)
+        .default_headers(headers)
+        .connect_timeout(Duration::from_secs(http.connect_timeout))
+        .read_timeout(Duration::from_secs(http.read_timeout));
+    if !http.proxy.is_empty() {
+        builder = builder.proxy(Proxy::all(&http.proxy)?);
+        println!("Sending requests through the configured proxy");
+    }
+    let fetcher = Fetcher {
+        client: builder.build()?,
+        limiter: RateLimiter::new(
+            Duration::from_millis(config.request_delay),
+            config.requests_per_minute,
+        ),
+        max_retries: config.max_retries,
+        cookies: jar,
+        cookie_path: cookie_path.to_path_buf(),
+    };
+    println!(
+        "Requests are at least {}ms apart",
+        fetcher.limiter.interval().as_millis()
+    );
+
+    // also do the patreon login if set to do so, unless a previous run's login is still saved
+    match site.password_login() {
+        Some(login) if config.patreon_prompt && fetcher.has_login_cookie(&login) => {
+            println!("Using saved patreon login");
+        }
+        Some(login) if config.patreon_prompt => {
+            let password = resolve_patreon_password(&config.patreon_password)?;
+
+            fetcher
+                .send(
+                    fetcher
+                        .client
+                        .post(login.url.clone())
+                        .form(&[(login.field, password.as_str()), ("Submit", "Submit")]),
+                )
+                .await?;
 
-    // also do the patreon login if set to do so
-    if parse_patreon {
-        let login_url = "https://wanderinginn.com/wp-login.php?action=postpass";
+            // wordpress sets this cookie for any password, whether it's right is only known
+            // once a protected chapter is downloaded, see PatreonLoginError
+            if !fetcher.has_login_cookie(&login) {
+                return Err(
+                    "Patreon login failed, the site did not accept the chapter password".into(),
+                );
+            }
+            fetcher.save_cookies()?;
+        }
+        None if config.patreon_prompt => {
+            println!("{} has no chapter password login", site.info().title);
+        }
+        _ => (),
+    }
+
+    Ok(fetcher)
+}
 
-        // prompt user for input value
-        let mut password = String::new();
-        print!("Enter patreon chapter password: ");
-        stdout().flush()?;
-        stdin().read_line(&mut password).unwrap();
-        let password = password.trim();
+/// The site answered with an error status, such as a rate limit or a cloudflare error page
+#[derive(Debug)]
+pub struct HttpError {
+    pub uri: String,
+    pub status: StatusCode,
+}
 
-        client
-            .post(login_url)
-            .header(USER_AGENT, "reqwest")
-            .form(&[("post_password", password), ("Submit", "Submit")])
-            .send()
-            .await?;
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned {}", self.uri, self.status)
     }
+}
+
+impl std::error::Error for HttpError {}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+// Exponential backoff starting at a second, with up to 50% jitter so retries don't line up
+fn backoff(attempt: u32) -> Duration {
+    let delay = Duration::from_secs(1)
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.5))
+}
 
-    Ok(client)
+// Retry-After is either a number of seconds or an http date
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    let delay = match value.trim().parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
 }
 
-async fn get_html(uri: String, client: &Client) -> Result<String, Box<dyn std::error::Error>> {
-    let resp = client.get(uri).header(USER_AGENT, "reqwest").send().await?;
-    let body = resp.text().await?;
-    Ok(body)
+enum Page {
+    NotModified,
+    Html {
+        body: String,
+        validators: db::Validators,
+    },
 }
 
+impl Fetcher {
+    // Sends the request once the rate limiter allows it, retrying rate limits, server errors and
+    // network failures up to max_retries times. Anything other than a 2xx or 304 response is
+    // returned as an HttpError
+    async fn send(&self, request: RequestBuilder) -> Result<Response, Box<dyn std::error::Error>> {
+        let mut attempt = 0;
+        loop {
+            let request = request.try_clone().ok_or("request body can't be retried")?;
+            self.limiter.acquire().await;
+            let wait = match request.send().await {
+                Ok(resp)
+                    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED =>
+                {
+                    return Ok(resp)
+                }
+                Ok(resp) if is_retryable(resp.status()) && attempt < self.max_retries => {
+                    println!("{} returned {}", resp.url(), resp.status());
+                    match retry_after(&resp) {
+                        // the server asked for a break from every request, not just this one
+                        Some(delay) => {
+                            self.limiter.pause(delay).await;
+                            delay
+                        }
+                        None => backoff(attempt),
+                    }
+                }
+                Ok(resp) => {
+                    return Err(Box::new(HttpError {
+                        uri: resp.url().to_string(),
+                        status: resp.status(),
+                    }))
+                }
+                Err(e)
+                    if (e.is_timeout() || e.is_connect() || e.is_request())
+                        && attempt < self.max_retries =>
+                {
+                    println!("Request failed: {}", e);
+                    backoff(attempt)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            attempt += 1;
+            println!(
+                "Retrying in {:.1}s ({}/{})",
+                wait.as_secs_f64(),
+                attempt,
+                self.max_retries
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn get_html(&self, uri: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let resp = self.send(self.client.get(uri)).await?;
+        let body = resp.text().await?;
+        Ok(body)
+    }
+
+    // Conditional GET, the server answers 304 if the page still matches the validators
+    async fn get_page(
+        &self,
+        uri: &str,
+        validators: &db::Validators,
+    ) -> Result<Page, Box<dyn std::error::Error>> {
+        let mut request = self.client.get(uri);
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let resp = self.send(request).await?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Page::NotModified);
+        }
+
+        let header = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let validators = db::Validators {
+            etag: header(resp.headers(), ETAG),
+            last_modified: header(resp.headers(), LAST_MODIFIED),
+        };
+        Ok(Page::Html {
+            body: resp.text().await?,
+            validators,
+        })
+    }
+}
+
+// What changed in the index compared to the table of contents, chapters are matched by uri
+#[derive(Debug, Default, PartialEq)]
+struct IndexChanges {
+    added: usize,
+    renamed: usize,
+    moved: usize,
+    removed: usize,
+    restored: usize,
+}
+
+// Below this share of the indexed chapters the table of contents was most likely only partly read
+const MIN_TOC_SHARE: f64 = 0.5;
+
 pub async fn update_index(
     db_conn: &Connection,
-    toc_url: &String,
-    client: &Client,
+    fetcher: &Fetcher,
+    site: &dyn SiteAdapter,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!("(Re)Building index");
 
-    let soup = Soup::new(&get_html(toc_url.to_string(), client).await?);
+    // the whole table of contents is read before touching the index, so a layout change
+    // can't leave it half updated
+    let volumes = match site.parse_toc(&fetcher.get_html(site.toc_url()).await?) {
+        Ok(volumes) => volumes,
+        Err(e) => {
+            println!("Leaving the index unchanged");
+            return Err(Box::new(e));
+        }
+    };
+
+    reconcile_index(db_conn, site, volumes, force)?;
+    fetcher.save_cookies()
+}
 
-    for volume in soup.class("volume-wrapper").find_all() {
-        let volume_title = volume.tag("h2").find().unwrap().text();
-        let volume_id: usize = db::add_volume(db_conn, &volume_title)?;
+// Brings the index in line with a table of contents that was read completely
+fn reconcile_index(
+    db_conn: &Connection,
+    site: &dyn SiteAdapter,
+    volumes: Vec<TocVolume>,
+    force: bool,
+) -> Result<IndexChanges, Box<dyn std::error::Error>> {
+    let mut known: HashMap<String, Vec<(db::Chapter, bool)>> = HashMap::new();
+    for (chapter, removed) in db::get_indexed_chapters(db_conn, site.series())? {
+        known
+            .entry(chapter.uri.clone())
+            .or_default()
+            .push((chapter, removed));
+    }
+    let listed: usize = volumes.iter().map(|volume| volume.chapters.len()).sum();
+    let indexed = known
+        .values()
+        .flatten()
+        .filter(|(_, removed)| !removed)
+        .count();
+    if !force && (listed as f64) < indexed as f64 * MIN_TOC_SHARE {
+        println!("Leaving the index unchanged");
+        return Err(format!(
+            "the table of contents lists {} chapters but {} are indexed, it may not have been \
+            read completely. Run index --force if the chapters really were removed",
+            listed, indexed
+        )
+        .into());
+    }
+    let mut volume_names: HashMap<usize, String> = db::get_volumes(db_conn, site.series())?
+        .into_iter()
+        .map(|volume| (volume.id, volume.name))
+        .collect();
+    let mut seen = HashSet::<usize>::new();
+    // uris of the chapters this run added, as they aren't in known
+    let mut added = HashSet::<String>::new();
+    let mut changes = IndexChanges::default();
+    // the first index of a series lists chapters published long before, none of them are recent
+    let indexed_at = if known.is_empty() {
+        None
+    } else {
+        Some(db::unix_now())
+    };
+
+    // positions follow the table of contents so epubs keep its order whenever chapters were added
+    let mut chapter_position = 0;
+    for (volume_position, volume) in volumes.into_iter().enumerate() {
+        let volume_title = volume.title;
+        let volume_id: usize =
+            db::add_volume(db_conn, site.series(), &volume_title, volume_position)?;
+        volume_names.insert(volume_id, volume_title.clone());
         let mut count = 0;
-        for chapter in volume.class("chapter-entry").find_all() {
-            let a = chapter.tag("a").find().unwrap();
-            let uri = a.get("href").unwrap();
-            let title = a.text();
-            db::add_chapter(db_conn, title, uri, volume_id)?;
+        for TocEntry { title, uri } in volume.chapters {
+            // the public version is listed now, replace the early access text with it
+            if !site.is_early_access(&title) {
+                let public = db::refetch_early_access(db_conn, site.series(), &uri)?;
+                if public > 0 {
+                    println!("{title} is out of patron early access, it will be downloaded again");
+                }
+            }
+
+            // older versions could leave several rows for one uri, prefer the one that already
+            // matches, then one with a download, the rest are removed below
+            let candidates = known.get(&uri).map(Vec::as_slice).unwrap_or_default();
+            let existing = candidates
+                .iter()
+                .filter(|(chapter, _)| !seen.contains(&chapter.id))
+                .max_by_key(|(chapter, removed)| {
+                    (
+                        chapter.name == title && chapter.volume_id == volume_id,
+                        !removed,
+                        chapter.data_id != 0,
+                        chapter.id,
+                    )
+                });
+            match existing {
+                None if !candidates.is_empty() || added.contains(&uri) => {
+                    // listed more than once, the first entry wins
+                    continue;
+                }
+                None => {
+                    db::add_chapter(
+                        db_conn,
+                        &title,
+                        &uri,
+                        volume_id,
+                        chapter_position,
+                        indexed_at,
+                    )?;
+                    println!("New chapter {} in {}", title, volume_title);
+                    added.insert(uri);
+                    changes.added += 1;
+                }
+                Some((chapter, removed)) => {
+                    seen.insert(chapter.id);
+                    db::update_indexed_chapter(
+                        db_conn,
+                        chapter.id,
+                        &title,
+                        volume_id,
+                        chapter_position,
+                    )?;
+                    let mut changed = false;
+                    if *removed {
+                        println!("{} is back in the table of contents", title);
+                        changes.restored += 1;
+                        changed = true;
+                    }
+                    if chapter.name != title {
+                        println!("Renamed {} to {}", chapter.name, title);
+                        changes.renamed += 1;
+                        changed = true;
+                    }
+                    if chapter.volume_id != volume_id {
+                        println!(
+                            "Moved {} from {} to {}",
+                            title,
+                            volume_names
+                                .get(&chapter.volume_id)
+                                .map(String::as_str)
+                                .unwrap_or("an unknown volume"),
+                            volume_title
+                        );
+                        db::update_generated_volume(db_conn, chapter.volume_id, true)?;
+                        changes.moved += 1;
+                        changed = true;
+                    }
+                    if changed && chapter.data_id != 0 {
+                        db::update_generated_chapter(db_conn, chapter.id, true)?;
+                        db::update_generated_volume(db_conn, volume_id, true)?;
+                    }
+                }
+            }
+            chapter_position += 1;
             count += 1;
         }
         println!("Indexed {volume_title} with {count} chapters");
     }
 
-    println!("Finished building index");
+    for (chapter, removed) in known.values().flatten() {
+        if *removed || seen.contains(&chapter.id) {
+            continue;
+        }
+        db::mark_removed(db_conn, chapter.id)?;
+        db::update_generated_volume(db_conn, chapter.volume_id, true)?;
+        println!(
+            "Removed {}, it is no longer in the table of contents",
+            chapter.name
+        );
+        changes.removed += 1;
+    }
 
-    Ok(())
+    println!(
+        "Finished building index: {} new, {} renamed, {} moved, {} removed, {} restored",
+        changes.added, changes.renamed, changes.moved, changes.removed, changes.restored
+    );
+    Ok(changes)
 }
 
-async fn download_chapter(
-    db_conn: &Connection,
-    chapter: db::Chapter,
-    parse_patreon: bool,
-    client: &Client,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut html_string = get_html(chapter.uri, client).await?;
-
-    let escape_re = Regex::new(r"(?:&)((?:lt|gt|nbsp);)").unwrap();
-    html_string = escape_re
-        .replace_all(&html_string, |captures: &regex::Captures| {
-            format!("&amp;{}", &captures[1])
-        })
-        .to_string();
-
-    let soup = Soup::new(&html_string);
-    let html = soup.class("entry-content").find().unwrap();
-
-    let header = "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"http://www.w3.org/TR/xhtml11    /DTD/xhtml11.dtd\">
+// Wraps the chapter text in a standalone xhtml document
+fn chapter_xhtml(info: &SeriesInfo, title: &str, content: &str) -> String {
+    let header = format!("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"http://www.w3.org/TR/xhtml11    /DTD/xhtml11.dtd\">
 <html xmlns=\"http://www.w3.org/1999/xhtml\">
 <head>
 <meta http-equiv=\"Content-Type\" content=\"text/html; charset=UTF-8\" />
-<meta name=\"author\" content=\"pirate aba\"/>
-<meta name=\"description\" content=\"The Wandering Inn\"/>
+<meta name=\"author\" content=\"{author}\"/>
+<meta name=\"description\" content=\"{title}\"/>
 <meta name=\"classification\" content=\"Fantasy\" />
-<title>The Wandering Inn</title>
+<title>{title}</title>
 <link rel=\"stylesheet\" href=\"style.css\" type = \"text/css\" />
 </head>
-<body>";
-
-    let title = format!(
-        "<h1>{}</h1>",
-        soup.class("entry-title").find().unwrap().text()
-    );
-
-    let patron_re = Regex::new(r"(?i)Patron Early Access").unwrap();
-    let is_patreon_chapter = patron_re.is_match(&title);
+<body>", author = info.author, title = info.title);
 
     let re = Regex::new(r"<a.*?</a>").unwrap();
-    let body = html.display();
     let footer = "</body></html>";
 
+    format!(
+        "{}\n<h1>{}</h1>\n{}\n{}\n",
+        header,
+        title,
+        re.replace_all(content, ""),
+        footer
+    )
+}
+
+// Stores a downloaded chapter page, returns whether the stored text changed
+fn store_chapter(
+    db_conn: &Connection,
+    chapter: db::Chapter,
+    page: Page,
+    site: &dyn SiteAdapter,
+    parse_patreon: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (html, validators) = match page {
+        Page::NotModified => {
+            db::clear_refetch(db_conn, chapter.id)?;
+            return Ok(false);
+        }
+        Page::Html { body, validators } => (body, validators),
+    };
+
+    let (title, content) = match site.parse_chapter(&html, &chapter.uri)? {
+        // never store the password form as chapter data
+        ChapterPage::Protected { title } => {
+            if parse_patreon {
+                return Err(Box::new(PatreonLoginError { uri: chapter.uri }));
+            }
+            if site.is_early_access(&title) {
+                db::remove_chapter(db_conn, chapter.id)?;
+            } else {
+                println!("Skipping password protected chapter {}", chapter.name);
+                db::clear_refetch(db_conn, chapter.id)?;
+            }
+            return Ok(false);
+        }
+        ChapterPage::Text { title, content } => (title, content),
+    };
+
+    let is_patreon_chapter = site.is_early_access(&title);
     if is_patreon_chapter && !parse_patreon {
         db::remove_chapter(db_conn, chapter.id)?;
+        Ok(false)
     } else {
-        db::add_chapter_data(
+        db::set_validators(db_conn, chapter.id, &validators)?;
+        Ok(db::add_chapter_data(
             db_conn,
             chapter.id,
-            &format!(
-                "{}\n{}\n{}\n{}\n",
-                header,
-                title,
-                re.replace_all(&body, ""),
-                footer
-            ),
-        )?;
+            &html,
+            &chapter_xhtml(site.info(), &title, &content),
+            is_patreon_chapter,
+        )?)
+    }
+}
+
+/// Rebuilds the xhtml of every downloaded chapter from its stored page without making any
+/// requests, so changes to the cleanup reach chapters downloaded before them
+pub fn reprocess_chapters(
+    db_conn: &Connection,
+    site: &dyn SiteAdapter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reprocessed = 0;
+    let mut changed = 0;
+    let mut missing = 0;
+    let mut repeated = 0;
+    for chapter in db::get_downloaded_chapters(db_conn, site.series())? {
+        // every revision is processed from its own page so they all match the current processing
+        let mut latest = None;
+        let mut latest_has_page = false;
+        for (revision_id, html) in db::get_revision_pages(db_conn, chapter.id)? {
+            latest = None;
+            latest_has_page = html.is_some();
+            let html = match html {
+                Some(html) => html,
+                None => continue,
+            };
+            match site.parse_chapter(&html, &chapter.uri)? {
+                ChapterPage::Text { title, content } => {
+                    let data = chapter_xhtml(site.info(), &title, &content);
+                    db::set_revision_data(db_conn, revision_id, &data)?;
+                    latest = Some(data);
+                }
+                // password forms are never stored, so this only happens if the selectors changed
+                ChapterPage::Protected { .. } => println!(
+                    "Skipping revision {} of {}, its stored page is password protected",
+                    revision_id, chapter.name
+                ),
+            }
+        }
+        repeated += db::remove_repeated_revisions(db_conn, chapter.id)?;
+
+        if !latest_has_page {
+            missing += 1;
+        }
+        if let Some(data) = latest {
+            reprocessed += 1;
+            if db::update_chapter_data(db_conn, chapter.id, &data)? {
+                println!("Updated {}", chapter.name);
+                changed += 1;
+            }
+        }
+    }
+
+    println!(
+        "Reprocessed {} chapters, {} changed and will be regenerated",
+        reprocessed, changed
+    );
+    if repeated > 0 {
+        println!(
+            "Removed {} revisions that came out the same as the one before them",
+            repeated
+        );
+    }
+    if missing > 0 {
+        println!(
+            "{} chapters were downloaded before their page was kept, \
+            `refresh --all` downloads them again so they can be reprocessed",
+            missing
+        );
     }
     Ok(())
 }
 
+/// Outcome of a download run, chapters that failed are left to be retried next time
+#[derive(Default)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    pub changed: usize,
+    pub failed: Vec<(String, String)>,
+    // chapters skipped because they failed recently
+    pub backed_off: usize,
+}
+
+const FIRST_FAILURE_BACKOFF: u64 = 60 * 60;
+const MAX_FAILURE_BACKOFF: u64 = 24 * 60 * 60;
+
+// Seconds to wait before trying a chapter again after it failed this many runs in a row,
+// starting at an hour and doubling up to a day
+fn failure_backoff(attempts: u32) -> u64 {
+    FIRST_FAILURE_BACKOFF
+        .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_FAILURE_BACKOFF)
+}
+
 pub async fn download_all_chapters(
     db_conn: &Connection,
-    delay: &u64,
+    fetcher: &Fetcher,
+    site: &dyn SiteAdapter,
     parse_patreon: bool,
-    client: &Client,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let chapters = db::get_empty_chapters(db_conn)?;
+    concurrent_downloads: usize,
+) -> Result<DownloadSummary, Box<dyn std::error::Error>> {
+    let now = db::unix_now();
+    let backed_off: HashSet<usize> = db::get_download_attempts(db_conn, site.series())?
+        .into_iter()
+        .filter(|attempt| {
+            attempt.last_error.is_some()
+                && now < attempt.last_try + failure_backoff(attempt.attempts)
+        })
+        .map(|attempt| attempt.chapter_id)
+        .collect();
+    let (chapters, waiting): (Vec<db::Chapter>, Vec<db::Chapter>) =
+        db::get_chapters_to_download(db_conn, site.series())?
+            .into_iter()
+            .partition(|chapter| !backed_off.contains(&chapter.id));
 
+    if !waiting.is_empty() {
+        println!(
+            "Waiting to retry {} chapters that failed recently, see `failures list`",
+            waiting.len()
+        );
+    }
+    let mut summary = download_chapters(
+        db_conn,
+        fetcher,
+        site,
+        chapters,
+        parse_patreon,
+        concurrent_downloads,
+    )
+    .await?;
+    summary.backed_off = waiting.len();
+    Ok(summary)
+}
+
+pub async fn download_chapters(
+    db_conn: &Connection,
+    fetcher: &Fetcher,
+    site: &dyn SiteAdapter,
+    chapters: Vec<db::Chapter>,
+    parse_patreon: bool,
+    concurrent_downloads: usize,
+) -> Result<DownloadSummary, Box<dyn std::error::Error>> {
     if chapters.is_empty() {
         println!("No chapters to download");
     } else {
-        println!("Downloading {} missing chapters", chapters.len());
+        println!(
+            "Downloading {} missing or outdated chapters",
+            chapters.len()
+        );
     }
-    let mut count = 0;
+
+    // only ask for changes if there's something stored to compare against
+    let mut requests = Vec::new();
     for chapter in chapters {
-        if count % 10 == 0 && count != 0 {
-            println!("Downloaded {} chapters", count);
+        let known = if chapter.data_id != 0 {
+            db::get_validators(db_conn, chapter.id)?
+        } else {
+            db::Validators::default()
+        };
+        requests.push((chapter, known));
+    }
+
+    // only the requests run concurrently, each page is stored as soon as it arrives
+    let mut pages = stream::iter(requests)
+        .map(|(chapter, known)| async move {
+            let page = match db::start_download_attempt(db_conn, chapter.id) {
+                Ok(()) => fetcher.get_page(&chapter.uri, &known).await,
+                Err(e) => Err(e.into()),
+            };
+            (chapter, page)
+        })
+        .buffer_unordered(concurrent_downloads.max(1));
+
+    let mut summary = DownloadSummary::default();
+    while let Some((chapter, page)) = pages.next().await {
+        let id = chapter.id;
+        let name = chapter.name.clone();
+        match page.and_then(|page| store_chapter(db_conn, chapter, page, site, parse_patreon)) {
+            Ok(changed) => {
+                db::clear_download_attempts(db_conn, id)?;
+                summary.downloaded += 1;
+                summary.changed += changed as usize;
+                if summary.downloaded % 10 == 0 {
+                    println!("Downloaded {} chapters", summary.downloaded);
+                }
+            }
+            Err(e) => {
+                // a rejected password fails every remaining patreon chapter the same way,
+                // and there's no point to keep a saved login that no longer works
+                if e.is::<PatreonLoginError>() {
+                    cookies::clear(&fetcher.cookie_path)?;
+                    return Err(e);
+                }
+                println!("Failed to download {}: {}", name, e);
+                db::fail_download_attempt(db_conn, id, &e.to_string())?;
+                summary.failed.push((name, e.to_string()));
+            }
         }
-        thread::sleep(Duration::from_millis(*delay));
-        download_chapter(db_conn, chapter, parse_patreon, client).await?;
-        count += 1;
     }
-    println!(
-        "{}",
-        if count > 0 {
-            format!("Done downloading {count} chapters")
-        } else {
-            "No chapters to download".to_string()
+    if summary.downloaded > 0 {
+        println!(
+            "Done downloading {} chapters, {} new or changed",
+            summary.downloaded, summary.changed
+        );
+    }
+    if !summary.failed.is_empty() {
+        println!("{} chapters failed to download:", summary.failed.len());
+        for (name, error) in &summary.failed {
+            println!("  {}: {}", name, error);
         }
-    );
-    Ok(())
+    }
+    fetcher.save_cookies()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WanderingInnConfig;
+    use crate::wandering_inn::WanderingInn;
+
+    fn site() -> WanderingInn {
+        WanderingInn::new(&WanderingInnConfig::default()).unwrap()
+    }
+
+    fn toc(volumes: &[(&str, &[(&str, &str)])]) -> Vec<TocVolume> {
+        volumes
+            .iter()
+            .map(|(title, chapters)| TocVolume {
+                title: title.to_string(),
+                chapters: chapters
+                    .iter()
+                    .map(|(title, uri)| TocEntry {
+                        title: title.to_string(),
+                        uri: uri.to_string(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // volume, name, uri and whether it was removed for every indexed chapter, oldest first
+    fn indexed(db_conn: &Connection) -> Vec<(String, String, String, bool)> {
+        let volumes: HashMap<usize, String> = db::get_volumes(db_conn, WanderingInn::SERIES)
+            .unwrap()
+            .into_iter()
+            .map(|volume| (volume.id, volume.name))
+            .collect();
+        db::get_indexed_chapters(db_conn, WanderingInn::SERIES)
+            .unwrap()
+            .into_iter()
+            .map(|(chapter, removed)| {
+                (
+                    volumes[&chapter.volume_id].clone(),
+                    chapter.name,
+                    chapter.uri,
+                    removed,
+                )
+            })
+            .collect()
+    }
+
+    fn indexed_once(volumes: &[(&str, &[(&str, &str)])]) -> Connection {
+        let db_conn = db::setup(Connection::open_in_memory().unwrap()).unwrap();
+        reconcile_index(&db_conn, &site(), toc(volumes), false).unwrap();
+        db_conn
+    }
+
+    fn entry(volume: &str, name: &str, uri: &str, removed: bool) -> (String, String, String, bool) {
+        (
+            volume.to_string(),
+            name.to_string(),
+            uri.to_string(),
+            removed,
+        )
+    }
+
+    #[test]
+    fn renamed_chapters_keep_their_row() {
+        let db_conn = indexed_once(&[("Volume 1", &[("1.00", "/1.00/"), ("1.01", "/1.01/")])]);
+        let changes = reconcile_index(
+            &db_conn,
+            &site(),
+            toc(&[(
+                "Volume 1",
+                &[("1.00 (Revised)", "/1.00/"), ("1.01", "/1.01/")],
+            )]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes.renamed, 1);
+        assert_eq!(changes.added, 0);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![
+                entry("Volume 1", "1.00 (Revised)", "/1.00/", false),
+                entry("Volume 1", "1.01", "/1.01/", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn moved_chapters_keep_their_row() {
+        let db_conn = indexed_once(&[("Volume 1", &[("1.00", "/1.00/"), ("1.01", "/1.01/")])]);
+        let changes = reconcile_index(
+            &db_conn,
+            &site(),
+            toc(&[
+                ("Volume 1", &[("1.00", "/1.00/")]),
+                ("Volume 2", &[("1.01", "/1.01/")]),
+            ]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes.moved, 1);
+        assert_eq!(changes.added, 0);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![
+                entry("Volume 1", "1.00", "/1.00/", false),
+                entry("Volume 2", "1.01", "/1.01/", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlisted_chapters_are_removed_and_restored() {
+        let volume: &[(&str, &str)] = &[("1.00", "/1.00/"), ("1.01", "/1.01/")];
+        let db_conn = indexed_once(&[("Volume 1", volume)]);
+
+        let changes = reconcile_index(
+            &db_conn,
+            &site(),
+            toc(&[("Volume 1", &[("1.00", "/1.00/")])]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes.removed, 1);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![
+                entry("Volume 1", "1.00", "/1.00/", false),
+                entry("Volume 1", "1.01", "/1.01/", true),
+            ]
+        );
+
+        let changes =
+            reconcile_index(&db_conn, &site(), toc(&[("Volume 1", volume)]), false).unwrap();
+        assert_eq!(changes.restored, 1);
+        assert_eq!(changes.added, 0);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![
+                entry("Volume 1", "1.00", "/1.00/", false),
+                entry("Volume 1", "1.01", "/1.01/", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_uris_keep_one_row() {
+        // listed twice in the table of contents
+        let db_conn =
+            indexed_once(&[("Volume 1", &[("1.00", "/1.00/"), ("1.00 again", "/1.00/")])]);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![entry("Volume 1", "1.00", "/1.00/", false)]
+        );
+
+        // left behind by older versions under another name
+        let volume_id = db::get_volumes(&db_conn, WanderingInn::SERIES).unwrap()[0].id;
+        db::add_chapter(&db_conn, "Old 1.00", "/1.00/", volume_id, 0, None).unwrap();
+        let changes = reconcile_index(
+            &db_conn,
+            &site(),
+            toc(&[("Volume 1", &[("1.00", "/1.00/")])]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes.removed, 1);
+        assert_eq!(
+            indexed(&db_conn),
+            vec![
+                entry("Volume 1", "1.00", "/1.00/", false),
+                entry("Volume 1", "Old 1.00", "/1.00/", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        for (attempt, base) in [(0, 1), (1, 2), (3, 8)] {
+            let delay = backoff(attempt);
+            let base = Duration::from_secs(base);
+            assert!(delay >= base && delay < base.mul_f64(1.5), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let delay = backoff(u32::MAX);
+        assert!(delay >= MAX_BACKOFF && delay < MAX_BACKOFF.mul_f64(1.5));
+    }
+
+    #[test]
+    fn failure_backoff_doubles_from_an_hour_up_to_a_day() {
+        assert_eq!(failure_backoff(0), FIRST_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(1), FIRST_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(2), 2 * FIRST_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(4), 8 * FIRST_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(6), MAX_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(u32::MAX), MAX_FAILURE_BACKOFF);
+    }
 }
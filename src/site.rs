@@ -0,0 +1,72 @@
+use reqwest::Url;
+
+use crate::config::Config;
+use crate::layout::LayoutError;
+use crate::wandering_inn::WanderingInn;
+
+/// Series the scraper has an adapter for, selected with the Series config value
+pub const SERIES: [&str; 1] = [WanderingInn::SERIES];
+
+/// Title and author of a serial, used in the stored chapters and the epub metadata
+pub struct SeriesInfo {
+    pub title: String,
+    pub author: String,
+}
+
+pub struct TocEntry {
+    pub title: String,
+    pub uri: String,
+}
+
+pub struct TocVolume {
+    pub title: String,
+    pub chapters: Vec<TocEntry>,
+}
+
+/// A chapter page as read from the site
+pub enum ChapterPage {
+    // the text is hidden behind a password that wasn't entered or was rejected
+    Protected { title: String },
+    Text { title: String, content: String },
+}
+
+/// The wordpress style login where a chapter password is posted once and remembered by a cookie
+pub struct PasswordLogin {
+    pub url: Url,
+    pub field: &'static str,
+    // prefix of the cookie the site sets once the password is posted
+    pub cookie: &'static str,
+}
+
+/// Everything that is specific to one web serial's site, the rest of the pipeline is shared
+pub trait SiteAdapter {
+    /// Short name the series is stored under in the database, e.g. "wandering-inn"
+    fn series(&self) -> &'static str;
+
+    fn info(&self) -> &SeriesInfo;
+
+    fn toc_url(&self) -> &str;
+
+    /// Reads every volume and chapter from the table of contents, in order
+    fn parse_toc(&self, html: &str) -> Result<Vec<TocVolume>, LayoutError>;
+
+    /// Reads the title and text of a downloaded chapter page
+    fn parse_chapter(&self, html: &str, uri: &str) -> Result<ChapterPage, LayoutError>;
+
+    /// Whether a chapter is only available to patrons for now and will be replaced later
+    fn is_early_access(&self, title: &str) -> bool;
+
+    fn password_login(&self) -> Option<PasswordLogin>;
+}
+
+/// Builds the adapter for the configured series, or returns every problem with its config
+pub fn adapter(config: &Config) -> Result<Box<dyn SiteAdapter>, Vec<String>> {
+    match config.series.as_str() {
+        WanderingInn::SERIES => Ok(Box::new(WanderingInn::new(&config.wandering_inn)?)),
+        series => Err(vec![format!(
+            "Series <{}> is not supported, use one of {}",
+            series,
+            SERIES.join(", ")
+        )]),
+    }
+}
@@ -0,0 +1,160 @@
+use regex::Regex;
+use reqwest::Url;
+use soup::prelude::*;
+
+use crate::config::WanderingInnConfig;
+use crate::layout::{Layout, LayoutError, Selector};
+use crate::site::{ChapterPage, PasswordLogin, SeriesInfo, SiteAdapter, TocEntry, TocVolume};
+
+/// wanderinginn.com, configured by the WanderingInn config section
+pub struct WanderingInn {
+    info: SeriesInfo,
+    toc_url: String,
+    layout: Layout,
+}
+
+impl WanderingInn {
+    pub const SERIES: &'static str = "wandering-inn";
+
+    pub fn new(config: &WanderingInnConfig) -> Result<WanderingInn, Vec<String>> {
+        let mut problems = Vec::<String>::new();
+        match Url::parse(&config.toc_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+            Ok(_) => problems.push(format!(
+                "WanderingInn.TocUrl <{}> is not an http(s) url",
+                config.toc_url
+            )),
+            Err(e) => problems.push(format!(
+                "WanderingInn.TocUrl <{}> is not a valid url: {}",
+                config.toc_url, e
+            )),
+        }
+        let layout = Layout::new(&config.selectors).map_err(|layout_problems| {
+            problems.extend(
+                layout_problems
+                    .iter()
+                    .map(|problem| format!("WanderingInn.{}", problem)),
+            )
+        });
+
+        match layout {
+            Ok(layout) if problems.is_empty() => Ok(WanderingInn {
+                info: SeriesInfo {
+                    title: String::from("The Wandering Inn"),
+                    author: String::from("pirate aba"),
+                },
+                toc_url: config.toc_url.clone(),
+                layout,
+            }),
+            _ => Err(problems),
+        }
+    }
+}
+
+impl SiteAdapter for WanderingInn {
+    fn series(&self) -> &'static str {
+        WanderingInn::SERIES
+    }
+
+    fn info(&self) -> &SeriesInfo {
+        &self.info
+    }
+
+    fn toc_url(&self) -> &str {
+        &self.toc_url
+    }
+
+    fn parse_toc(&self, html: &str) -> Result<Vec<TocVolume>, LayoutError> {
+        let layout = &self.layout;
+        let soup = Soup::new(html);
+        let missing = |selector: &Selector, context: String| LayoutError {
+            uri: self.toc_url.clone(),
+            selector: selector.to_string(),
+            context,
+        };
+
+        let mut volumes = Vec::new();
+        for (i, volume) in layout.volume.find_all(&soup).enumerate() {
+            let title = layout
+                .volume_title
+                .find(&volume)
+                .ok_or_else(|| missing(&layout.volume_title, format!("volume {}", i + 1)))?
+                .text();
+            let mut chapters = Vec::new();
+            for (j, chapter) in layout.chapter_entry.find_all(&volume).enumerate() {
+                let context = || format!("chapter entry {} of {}", j + 1, title);
+                let link = layout
+                    .chapter_link
+                    .find(&chapter)
+                    .ok_or_else(|| missing(&layout.chapter_link, context()))?;
+                chapters.push(TocEntry {
+                    uri: link.get("href").ok_or_else(|| LayoutError {
+                        uri: self.toc_url.clone(),
+                        selector: format!("{}[href]", layout.chapter_link),
+                        context: context(),
+                    })?,
+                    title: link.text(),
+                });
+            }
+            volumes.push(TocVolume { title, chapters });
+        }
+
+        // an empty table of contents would mark every chapter as removed
+        if volumes.is_empty() {
+            return Err(missing(
+                &layout.volume,
+                String::from("the table of contents"),
+            ));
+        }
+        if volumes.iter().all(|volume| volume.chapters.is_empty()) {
+            return Err(missing(&layout.chapter_entry, String::from("any volume")));
+        }
+        Ok(volumes)
+    }
+
+    fn parse_chapter(&self, html: &str, uri: &str) -> Result<ChapterPage, LayoutError> {
+        let layout = &self.layout;
+        let escape_re = Regex::new(r"(?:&)((?:lt|gt|nbsp);)").unwrap();
+        let html_string = escape_re
+            .replace_all(html, |captures: &regex::Captures| {
+                format!("&amp;{}", &captures[1])
+            })
+            .to_string();
+
+        let soup = Soup::new(&html_string);
+        let missing = |selector: &Selector| LayoutError {
+            uri: uri.to_string(),
+            selector: selector.to_string(),
+            context: String::from("the chapter page"),
+        };
+
+        let title = layout
+            .entry_title
+            .find(&soup)
+            .ok_or_else(|| missing(&layout.entry_title))?
+            .text();
+
+        if layout.password_form.find(&soup).is_some() {
+            return Ok(ChapterPage::Protected { title });
+        }
+
+        let content = layout
+            .entry_content
+            .find(&soup)
+            .ok_or_else(|| missing(&layout.entry_content))?
+            .display();
+        Ok(ChapterPage::Text { title, content })
+    }
+
+    fn is_early_access(&self, title: &str) -> bool {
+        self.layout.is_early_access(title)
+    }
+
+    fn password_login(&self) -> Option<PasswordLogin> {
+        Some(PasswordLogin {
+            url: Url::parse("https://wanderinginn.com/wp-login.php?action=postpass").unwrap(),
+            field: "post_password",
+            cookie: "wp-postpass_",
+        })
+    }
+}